@@ -0,0 +1,406 @@
+pub mod api_keys {
+
+    // Who may send x-ulysses-key, and what each of them may do with it. Every service gets a key
+    // of its own, described in a JSON key file (config/api_keys.json unless HUB_API_KEYS_PATH says
+    // otherwise), so one can be told apart from another and revoked without touching the rest:
+    //
+    // {
+    //   "keys": [
+    //     {
+    //       "id": "website",                   recorded on every metric the key sends (key_id)
+    //       "service": "couch-gag-website",    who holds it, also the metric's source if the request doesn't say
+    //       "hashed_key": "sha256$...$...",    from `couch-gag-metrics-hub hash-key`, never the key itself
+    //       "scopes": ["ingest"],              ingest | query | admin
+    //       "metric_types": ["story-view"],    wire keys it may record, leave out to allow any
+    //       "expires_at": 1798761600,          unix seconds, leave out for a key that doesn't expire
+    //       "previous_keys": [                 generations rotated out, still accepted until valid_until
+    //         { "hashed_key": "sha256$...$...", "valid_until": 1767225600 }
+    //       ]
+    //     }
+    //   ]
+    // }
+    //
+    // Rotating a key is: hash the new one, move the old hashed_key into previous_keys with a cutoff,
+    // SIGHUP the hub, then move the service over whenever it next deploys. Which generation every
+    // request came with is counted (couch_gag_hub_api_key_requests_total), once "previous-1" stops
+    // showing up it can be dropped from the file.
+    //
+    // ULYSSES_HASHED_KEY, the one key shared by everything before there was a key file, is still
+    // taken as the key "shared", with every scope. ULYSSES_PREVIOUS_HASHED_KEY and
+    // ULYSSES_PREVIOUS_KEY_VALID_UNTIL are its previous generation.
+
+    use crate::config::hub_config::HubConfig;
+    use crate::metrics::metric::MetricName;
+    use crate::registry::metric_registry::MetricRegistry;
+    use crate::ulysses_key::ulysses_key::HashedKey;
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::path::Path;
+
+    pub const SHARED_KEY_ID: &str = "shared";
+
+    // what a key is good for, every route that takes a key asks for one of these
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    pub enum Scope {
+        // recording metrics, and minting beacon tokens for browsers to record them
+        Ingest,
+        // reading them back, /query, /stories/:id/metrics and the Prometheus scrape
+        Query,
+        // the hub's own state, /admin/*
+        Admin,
+    }
+
+    impl Scope {
+        pub fn from_name(name: &str) -> Option<Scope> {
+            match name {
+                "ingest" => Some(Scope::Ingest),
+                "query" => Some(Scope::Query),
+                "admin" => Some(Scope::Admin),
+                _ => None,
+            }
+        }
+
+        pub fn get_name(&self) -> &'static str {
+            match self {
+                Scope::Ingest => "ingest",
+                Scope::Query => "query",
+                Scope::Admin => "admin",
+            }
+        }
+    }
+
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct PreviousKeyDefinition {
+        hashed_key: String,
+        valid_until: u64,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct ApiKeyDefinition {
+        id: String,
+        service: String,
+        hashed_key: String,
+        scopes: Vec<String>,
+        #[serde(default)]
+        metric_types: Option<Vec<String>>,
+        #[serde(default)]
+        expires_at: Option<u64>,
+        #[serde(default)]
+        previous_keys: Vec<PreviousKeyDefinition>,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct KeyFile {
+        keys: Vec<ApiKeyDefinition>,
+    }
+
+    // which of a key's hashes a request matched
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    pub enum KeyGeneration {
+        Active,
+        // index 1 is the most recently rotated out, as listed in previous_keys
+        Previous { index: usize, valid_until: u64 },
+    }
+
+    impl KeyGeneration {
+        // "active", "previous-1", "previous-2"...
+        pub fn get_label(&self) -> String {
+            match self {
+                KeyGeneration::Active => String::from("active"),
+                KeyGeneration::Previous { index, .. } => format!("previous-{}", index),
+            }
+        }
+
+        // the active generation is never retired, a previous one is from its cutoff on
+        pub fn is_retired(&self, now_secs: u64) -> bool {
+            matches!(self, KeyGeneration::Previous { valid_until, .. } if *valid_until <= now_secs)
+        }
+    }
+
+    #[derive(Clone, Debug)]
+    struct PreviousKey {
+        hashed_key: HashedKey,
+        valid_until: u64,
+    }
+
+    #[derive(Clone, Debug)]
+    pub struct ApiKey {
+        pub id: String,
+        pub service: String,
+        hashed_key: HashedKey,
+        pub scopes: Vec<Scope>,
+        // None when any metric type goes
+        pub metric_types: Option<Vec<MetricName>>,
+        pub expires_at: Option<u64>,
+        previous_keys: Vec<PreviousKey>,
+    }
+
+    impl ApiKey {
+        pub fn has_scope(&self, scope: Scope) -> bool {
+            self.scopes.contains(&scope)
+        }
+
+        pub fn allows_metric_type(&self, metric_type: &MetricName) -> bool {
+            match &self.metric_types {
+                Some(metric_types) => metric_types.contains(metric_type),
+                None => true,
+            }
+        }
+
+        pub fn is_expired(&self, now_secs: u64) -> bool {
+            matches!(self.expires_at, Some(expires_at) if expires_at <= now_secs)
+        }
+
+        // every previous generation, retired ones included
+        pub fn get_previous_generations(&self) -> Vec<KeyGeneration> {
+            self.previous_keys
+                .iter()
+                .enumerate()
+                .map(|(position, previous)| KeyGeneration::Previous {
+                    index: position + 1,
+                    valid_until: previous.valid_until,
+                })
+                .collect()
+        }
+
+        // the generation presented hashes to, checking every one of them whatever matched first
+        fn match_generation(&self, presented: &str, pepper: &str) -> Option<KeyGeneration> {
+            let active = if self.hashed_key.verify(presented, pepper) { Some(KeyGeneration::Active) } else { None };
+            self.get_previous_generations()
+                .into_iter()
+                .zip(self.previous_keys.iter())
+                .fold(active, |found, (generation, previous)| {
+                    let matches = previous.hashed_key.verify(presented, pepper);
+                    found.or(if matches { Some(generation) } else { None })
+                })
+        }
+    }
+
+    // the key a request authenticated with, and which of its generations it used
+    pub struct KeyMatch<'k> {
+        pub key: &'k ApiKey,
+        pub generation: KeyGeneration,
+    }
+
+    pub struct ApiKeyStore {
+        keys: Vec<ApiKey>,
+    }
+
+    impl ApiKeyStore {
+        // The keys in the config's key file, plus the shared key if one is configured.
+        // A missing file just means there are no per service keys. Err holds every problem found,
+        // the metric types are checked against the registry so a typo can't lock a service out.
+        pub fn load(config: &HubConfig, registry: &MetricRegistry) -> Result<ApiKeyStore, Vec<String>> {
+            let path = Path::new(&config.api_keys_path);
+            let mut store = ApiKeyStore { keys: Vec::new() };
+            if let Some(shared_key) = &config.ulysses_hashed_key {
+                let previous_keys = match (&config.ulysses_previous_hashed_key, config.ulysses_previous_key_valid_until) {
+                    (Some(hashed_key), Some(valid_until)) => vec![PreviousKey {
+                        hashed_key: hashed_key.clone(),
+                        valid_until,
+                    }],
+                    _ => vec![],
+                };
+                store.keys.push(ApiKey {
+                    id: String::from(SHARED_KEY_ID),
+                    service: String::from(SHARED_KEY_ID),
+                    hashed_key: shared_key.clone(),
+                    scopes: vec![Scope::Ingest, Scope::Query, Scope::Admin],
+                    metric_types: None,
+                    expires_at: None,
+                    previous_keys,
+                });
+            }
+
+            let contents = match fs::read_to_string(path) {
+                Ok(contents) => contents,
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(store),
+                Err(e) => return Err(vec![format!("could not read key file {} ({})", path.display(), e)]),
+            };
+            let key_file: KeyFile = serde_json::from_str(&contents)
+                .map_err(|e| vec![format!("key file {} is not valid ({})", path.display(), e)])?;
+
+            let mut errors: Vec<String> = Vec::new();
+            for definition in key_file.keys {
+                match ApiKeyStore::read_definition(definition, registry) {
+                    Ok(key) if store.keys.iter().any(|existing| existing.id == key.id) => {
+                        errors.push(format!("key file {}: key '{}' is defined more than once", path.display(), key.id));
+                    }
+                    Ok(key) => store.keys.push(key),
+                    Err(key_errors) => {
+                        errors.extend(key_errors.into_iter().map(|e| format!("key file {}: {}", path.display(), e)));
+                    }
+                }
+            }
+
+            if errors.is_empty() {
+                Ok(store)
+            } else {
+                Err(errors)
+            }
+        }
+
+        fn read_definition(definition: ApiKeyDefinition, registry: &MetricRegistry) -> Result<ApiKey, Vec<String>> {
+            let mut errors: Vec<String> = Vec::new();
+            let id = definition.id;
+
+            let is_valid_id = !id.is_empty() && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+            if !is_valid_id {
+                errors.push(format!("'{}' is not a valid key id, use letters, digits, '-' or '_'", id));
+            }
+            if definition.service.trim().is_empty() {
+                errors.push(format!("{}: service must not be empty", id));
+            }
+
+            let hashed_key = HashedKey::parse(&definition.hashed_key).map_err(|message| {
+                errors.push(format!("{}: hashed_key {}, run `couch-gag-metrics-hub hash-key` to hash a key", id, message));
+            });
+
+            let mut previous_keys: Vec<PreviousKey> = Vec::new();
+            for (position, previous) in definition.previous_keys.iter().enumerate() {
+                match HashedKey::parse(&previous.hashed_key) {
+                    Ok(hashed_key) => previous_keys.push(PreviousKey {
+                        hashed_key,
+                        valid_until: previous.valid_until,
+                    }),
+                    Err(message) => errors.push(format!("{}: previous_keys[{}].hashed_key {}", id, position, message)),
+                }
+            }
+
+            let mut scopes: Vec<Scope> = Vec::new();
+            for name in definition.scopes.iter() {
+                match Scope::from_name(name) {
+                    Some(scope) if !scopes.contains(&scope) => scopes.push(scope),
+                    Some(_) => {}
+                    None => errors.push(format!("{}: '{}' is not a scope, expected ingest, query or admin", id, name)),
+                }
+            }
+            if definition.scopes.is_empty() {
+                errors.push(format!("{}: a key needs at least one scope", id));
+            }
+
+            let metric_types = definition.metric_types.map(|wire_keys| {
+                wire_keys
+                    .iter()
+                    .filter_map(|wire_key| {
+                        let metric_type = registry.get_metric_type_from_wire_key(wire_key);
+                        if metric_type.is_none() {
+                            errors.push(format!("{}: '{}' is not a known metric type", id, wire_key));
+                        }
+                        metric_type
+                    })
+                    .collect()
+            });
+
+            match hashed_key {
+                Ok(hashed_key) if errors.is_empty() => Ok(ApiKey {
+                    id,
+                    service: definition.service,
+                    hashed_key,
+                    scopes,
+                    metric_types,
+                    expires_at: definition.expires_at,
+                    previous_keys,
+                }),
+                _ => Err(errors),
+            }
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.keys.is_empty()
+        }
+
+        pub fn get_keys(&self) -> &[ApiKey] {
+            &self.keys
+        }
+
+        // the key with this id, for beacon tokens which carry the id of the key that minted them
+        pub fn get(&self, id: &str) -> Option<&ApiKey> {
+            self.keys.iter().find(|key| key.id == id)
+        }
+
+        // The key a client's x-ulysses-key belongs to, and its generation. Every key is tried,
+        // even after a match, so how long this takes says nothing about which key matched.
+        pub fn find(&self, presented: &str, pepper: &str) -> Option<KeyMatch<'_>> {
+            self.keys.iter().fold(None, |found, key| {
+                let generation = key.match_generation(presented, pepper);
+                found.or(generation.map(|generation| KeyMatch { key, generation }))
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const PEPPER: &str = "pepper";
+        const CUTOFF: u64 = 1_700_000_000;
+
+        fn make_key(id: &str, active: &str, previous: &[(&str, u64)]) -> ApiKey {
+            ApiKey {
+                id: String::from(id),
+                service: String::from(id),
+                hashed_key: HashedKey::hash(active, PEPPER).unwrap(),
+                scopes: vec![Scope::Ingest],
+                metric_types: None,
+                expires_at: None,
+                previous_keys: previous
+                    .iter()
+                    .map(|(key, valid_until)| PreviousKey {
+                        hashed_key: HashedKey::hash(key, PEPPER).unwrap(),
+                        valid_until: *valid_until,
+                    })
+                    .collect(),
+            }
+        }
+
+        fn find_generation(store: &ApiKeyStore, presented: &str) -> Option<(String, KeyGeneration)> {
+            store.find(presented, PEPPER).map(|found| (found.key.id.clone(), found.generation))
+        }
+
+        #[test]
+        fn finds_the_key_and_generation_a_request_used() {
+            let store = ApiKeyStore {
+                keys: vec![
+                    make_key("website", "website-new", &[("website-old", CUTOFF), ("website-older", CUTOFF - 10)]),
+                    make_key("grafana", "grafana-key", &[]),
+                ],
+            };
+
+            assert_eq!(find_generation(&store, "website-new"), Some((String::from("website"), KeyGeneration::Active)));
+            assert_eq!(find_generation(&store, "grafana-key"), Some((String::from("grafana"), KeyGeneration::Active)));
+            assert_eq!(
+                find_generation(&store, "website-older"),
+                Some((String::from("website"), KeyGeneration::Previous { index: 2, valid_until: CUTOFF - 10 }))
+            );
+            assert_eq!(find_generation(&store, "unknown"), None);
+            assert_eq!(store.find("website-new", "wrong-pepper").map(|found| found.generation), None);
+        }
+
+        #[test]
+        fn accepts_a_previous_generation_only_before_its_cutoff() {
+            let store = ApiKeyStore { keys: vec![make_key("website", "website-new", &[("website-old", CUTOFF)])] };
+
+            let (_, generation) = find_generation(&store, "website-old").unwrap();
+            assert_eq!(generation, KeyGeneration::Previous { index: 1, valid_until: CUTOFF });
+            assert_eq!(generation.get_label(), "previous-1");
+            assert!(!generation.is_retired(CUTOFF - 1));
+            assert!(generation.is_retired(CUTOFF));
+            assert!(generation.is_retired(CUTOFF + 1));
+        }
+
+        #[test]
+        fn the_active_generation_wins_and_is_never_retired() {
+            // rotated back to a key that's also still listed as a previous generation
+            let store = ApiKeyStore { keys: vec![make_key("website", "same-key", &[("same-key", CUTOFF)])] };
+
+            let (_, generation) = find_generation(&store, "same-key").unwrap();
+            assert_eq!(generation, KeyGeneration::Active);
+            assert_eq!(generation.get_label(), "active");
+            assert!(!generation.is_retired(u64::MAX));
+        }
+    }
+}
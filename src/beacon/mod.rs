@@ -0,0 +1,177 @@
+pub mod beacon_token {
+
+    // navigator.sendBeacon can't attach an x-ulysses-key header, and the key must never be shipped
+    // to browsers anyway. Instead couch-gag-website's server asks the hub for a short lived token
+    // (GET /beacon/token, with its ulysses key), renders it into the page, and the browser sends it
+    // back inside the beacon payload.
+    //
+    // A token is "<expiry as unix seconds>.<key id>.<hex HMAC-SHA256 of both>", signed with a secret
+    // only the hub knows, so the hub can check it without keeping any state. The key id is the api key
+    // that asked for the token: metrics sent with it are held to that key's scopes and metric types,
+    // and recorded under its id. Tokens minted before key ids were carried are
+    // "<expiry>.<hex HMAC-SHA256 of the expiry>", only the shared key minted those, so the hub holds
+    // them to the shared key until they expire.
+
+    use crate::utils::utils::{decode_hex, encode_hex};
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    type HmacSha256 = Hmac<Sha256>;
+
+    // everything signed is prefixed, so a signature minted here can't be replayed as anything else
+    const TOKEN_SIGNING_CONTEXT: &str = "couch-gag-beacon:";
+
+    // what a token that checked out says
+    #[derive(Debug, PartialEq)]
+    pub struct BeaconToken {
+        pub expires_at: u64,
+        // None for a token minted without a key, or before tokens carried one
+        pub key_id: Option<String>,
+    }
+
+    // key ids can't contain '.', see ApiKeyStore, or the token couldn't be split back up
+    pub fn mint_token(secret: &str, expires_at: u64, key_id: Option<&str>) -> String {
+        debug_assert!(
+            !matches!(key_id, Some(key_id) if key_id.is_empty() || key_id.contains('.')),
+            "beacon token key ids must be non empty and can't contain '.'"
+        );
+        let signature = encode_hex(&sign(secret, expires_at, key_id));
+        match key_id {
+            Some(key_id) => format!("{}.{}.{}", expires_at, key_id, signature),
+            None => format!("{}.{}", expires_at, signature),
+        }
+    }
+
+    // Ok for a token this hub signed that hasn't expired yet, Err(reason) otherwise
+    pub fn verify_token(secret: &str, token: &str, now: u64) -> Result<BeaconToken, String> {
+        let parts: Vec<&str> = token.split('.').collect();
+        let (expires_at_str, key_id, signature_hex) = match parts.as_slice() {
+            [expires_at, key_id, signature] if !key_id.is_empty() => (*expires_at, Some(*key_id), *signature),
+            [expires_at, signature] => (*expires_at, None, *signature),
+            _ => return Err(String::from("token is malformed")),
+        };
+
+        let expires_at = match expires_at_str.parse::<u64>() {
+            Ok(expires_at) => expires_at,
+            Err(_) => return Err(String::from("token is malformed")),
+        };
+        let signature = match decode_hex(signature_hex) {
+            Some(signature) => signature,
+            None => return Err(String::from("token is malformed")),
+        };
+
+        let mut mac = new_mac(secret);
+        mac.update(get_signed_message(expires_at, key_id).as_bytes());
+        // verify_slice compares in constant time
+        if mac.verify_slice(&signature).is_err() {
+            return Err(String::from("token signature is invalid"));
+        }
+
+        if expires_at <= now {
+            return Err(String::from("token has expired"));
+        }
+
+        Ok(BeaconToken {
+            expires_at,
+            key_id: key_id.map(String::from),
+        })
+    }
+
+    fn sign(secret: &str, expires_at: u64, key_id: Option<&str>) -> Vec<u8> {
+        let mut mac = new_mac(secret);
+        mac.update(get_signed_message(expires_at, key_id).as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    // the '.' keeps an old expiry-only signature from ever passing for a token with a key id
+    fn get_signed_message(expires_at: u64, key_id: Option<&str>) -> String {
+        match key_id {
+            Some(key_id) => format!("{}{}.{}", TOKEN_SIGNING_CONTEXT, expires_at, key_id),
+            None => format!("{}{}", TOKEN_SIGNING_CONTEXT, expires_at),
+        }
+    }
+
+    fn new_mac(secret: &str) -> HmacSha256 {
+        // HMAC takes keys of any length, so this can't actually fail
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length")
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const SECRET: &str = "beacon-secret";
+        const NOW: u64 = 1_700_000_000;
+
+        #[test]
+        fn round_trips_with_and_without_a_key_id() {
+            let token = mint_token(SECRET, NOW + 60, Some("website"));
+            let verified = verify_token(SECRET, &token, NOW).unwrap();
+            assert_eq!(verified, BeaconToken { expires_at: NOW + 60, key_id: Some(String::from("website")) });
+
+            let token = mint_token(SECRET, NOW + 60, None);
+            assert_eq!(verify_token(SECRET, &token, NOW).unwrap(), BeaconToken { expires_at: NOW + 60, key_id: None });
+        }
+
+        #[test]
+        fn refuses_tampered_tokens() {
+            let token = mint_token(SECRET, NOW + 60, Some("website"));
+            let parts: Vec<&str> = token.split('.').collect();
+
+            let mut signature = String::from(parts[2]);
+            let last = if signature.ends_with('0') { "1" } else { "0" };
+            signature.replace_range(signature.len() - 1.., last);
+            let tampered = format!("{}.{}.{}", parts[0], parts[1], signature);
+            assert_eq!(verify_token(SECRET, &tampered, NOW), Err(String::from("token signature is invalid")));
+
+            let swapped_key = format!("{}.{}.{}", parts[0], "admin-tool", parts[2]);
+            assert_eq!(verify_token(SECRET, &swapped_key, NOW), Err(String::from("token signature is invalid")));
+
+            let extended = format!("{}.{}.{}", NOW + 6000, parts[1], parts[2]);
+            assert_eq!(verify_token(SECRET, &extended, NOW), Err(String::from("token signature is invalid")));
+
+            assert_eq!(verify_token("another-secret", &token, NOW), Err(String::from("token signature is invalid")));
+        }
+
+        #[test]
+        fn a_legacy_signature_cannot_carry_a_key_id() {
+            let legacy = mint_token(SECRET, NOW + 60, None);
+            let (expires_at, signature) = legacy.split_once('.').unwrap();
+
+            let with_key_id = format!("{}.{}.{}", expires_at, "website", signature);
+            assert_eq!(verify_token(SECRET, &with_key_id, NOW), Err(String::from("token signature is invalid")));
+        }
+
+        #[test]
+        fn refuses_tokens_at_their_expiry() {
+            let token = mint_token(SECRET, NOW, Some("website"));
+            assert_eq!(verify_token(SECRET, &token, NOW), Err(String::from("token has expired")));
+            assert!(verify_token(SECRET, &token, NOW - 1).is_ok());
+        }
+
+        #[test]
+        fn refuses_malformed_tokens() {
+            let token = mint_token(SECRET, NOW + 60, Some("website"));
+            let signature = token.rsplit('.').next().unwrap();
+
+            for malformed in [
+                String::from(""),
+                String::from("not-a-token"),
+                format!("soon.website.{}", signature),
+                format!("{}.website.{}", NOW + 60, &signature[1..]),
+                format!("{}.website.{}zz", NOW + 60, &signature[2..]),
+                format!("{}..{}", NOW + 60, signature),
+                format!("{}.website.extra.{}", NOW + 60, signature),
+            ]
+            .iter()
+            {
+                assert_eq!(
+                    verify_token(SECRET, malformed, NOW),
+                    Err(String::from("token is malformed")),
+                    "{} should be malformed",
+                    malformed
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,390 @@
+pub mod hub_config {
+
+    // Everything the hub can be configured with, read once at startup (and again on SIGHUP)
+    // instead of going back to the environment or .env whenever a setting is needed.
+    //
+    // Each setting can come from, later ones winning:
+    //   1. its default
+    //   2. the TOML file at HUB_CONFIG_PATH (config/hub.toml unless set), by its TOML key, if the file exists
+    //   3. the .env file, by its environment key
+    //   4. the process environment, by its environment key
+    //
+    // Every value is checked before the config is used, a bad one is an error naming the
+    // setting and where it came from rather than a silent fall back to the default.
+
+    use crate::dotenv::dotenv;
+    use crate::ulysses_key::ulysses_key::HashedKey;
+    use std::collections::HashMap;
+    use std::fmt;
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::path::Path;
+
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    pub enum ConfigSource {
+        Default,
+        TomlFile,
+        DotEnv,
+        Environment,
+    }
+
+    impl fmt::Display for ConfigSource {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                ConfigSource::Default => write!(f, "default"),
+                ConfigSource::TomlFile => write!(f, "toml file"),
+                ConfigSource::DotEnv => write!(f, ".env"),
+                ConfigSource::Environment => write!(f, "environment"),
+            }
+        }
+    }
+
+    // (TOML key, environment key) of every setting there is
+    const SETTINGS: [(&str, &str); 22] = [
+        ("ulysses_hashed_key", "ULYSSES_HASHED_KEY"),
+        ("ulysses_previous_hashed_key", "ULYSSES_PREVIOUS_HASHED_KEY"),
+        ("ulysses_previous_key_valid_until", "ULYSSES_PREVIOUS_KEY_VALID_UNTIL"),
+        ("ulysses_key_pepper", "ULYSSES_KEY_PEPPER"),
+        ("api_keys_path", "HUB_API_KEYS_PATH"),
+        ("beacon_secret", "ULYSSES_BEACON_SECRET"),
+        ("beacon_token_ttl_secs", "HUB_BEACON_TOKEN_TTL_SECS"),
+        ("beacon_token_max_ttl_secs", "HUB_BEACON_TOKEN_MAX_TTL_SECS"),
+        ("metrics_log_path", "METRICS_LOG_PATH"),
+        ("metric_registry_path", "METRIC_REGISTRY_PATH"),
+        ("strict_metric_types", "HUB_STRICT_METRIC_TYPES"),
+        ("worker_count", "HUB_WORKER_COUNT"),
+        ("queue_depth", "HUB_QUEUE_DEPTH"),
+        ("keep_alive_idle_secs", "HUB_KEEP_ALIVE_IDLE_SECS"),
+        ("keep_alive_max_requests", "HUB_KEEP_ALIVE_MAX_REQUESTS"),
+        ("shutdown_grace_secs", "HUB_SHUTDOWN_GRACE_SECS"),
+        ("quarantine_capacity", "HUB_QUARANTINE_CAPACITY"),
+        ("query_window_size", "HUB_QUERY_WINDOW_SIZE"),
+        ("prometheus_max_targets", "HUB_PROMETHEUS_MAX_TARGETS"),
+        ("prometheus_max_tag_keys", "HUB_PROMETHEUS_MAX_TAG_KEYS"),
+        ("prometheus_max_tag_values", "HUB_PROMETHEUS_MAX_TAG_VALUES"),
+        ("prometheus_max_series", "HUB_PROMETHEUS_MAX_SERIES"),
+    ];
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct HubConfig {
+        // Picked up again by a reload, these are read per request
+
+        // a salted hash of the key shared by every service that has no key of its own (see HashedKey),
+        // without it or the key file only beacons and pixels get in
+        pub ulysses_hashed_key: Option<HashedKey>,
+        // the shared key's hash before it was last rotated, accepted until the cutoff (unix seconds)
+        pub ulysses_previous_hashed_key: Option<HashedKey>,
+        pub ulysses_previous_key_valid_until: Option<u64>,
+        // mixed into every key's hash, kept apart from them
+        pub ulysses_key_pepper: Option<String>,
+        // the per service keys, see ApiKeyStore, read again on every reload
+        pub api_keys_path: String,
+        // signs beacon tokens, none means beacon ingestion is off
+        pub beacon_secret: Option<String>,
+        pub beacon_token_ttl_secs: u64,
+        pub beacon_token_max_ttl_secs: u64,
+
+        // Only read at startup, a reload that changes them just warns that a restart is needed
+
+        pub metrics_log_path: String,
+        pub metric_registry_path: String,
+        // reject unknown or missing metric keys instead of recording them as Error / Base
+        pub strict_metric_types: bool,
+        pub worker_count: usize,
+        pub queue_depth: usize,
+        pub keep_alive_idle_secs: u64,
+        pub keep_alive_max_requests: usize,
+        pub shutdown_grace_secs: u64,
+        pub quarantine_capacity: usize,
+        // how many of the most recent metrics /query and /stories/:id/metrics can see, see RecentMetrics
+        pub query_window_size: usize,
+        pub prometheus_max_targets: usize,
+        pub prometheus_max_tag_keys: usize,
+        pub prometheus_max_tag_values: usize,
+        pub prometheus_max_series: usize,
+    }
+
+    // a setting's value before it's parsed, and where it came from
+    struct RawValue {
+        value: String,
+        source: ConfigSource,
+    }
+
+    // the raw values of every setting that was set anywhere, by TOML key
+    struct RawSettings {
+        values: HashMap<&'static str, RawValue>,
+        errors: Vec<String>,
+    }
+
+    impl RawSettings {
+        fn set(&mut self, toml_key: &'static str, value: String, source: ConfigSource) {
+            self.values.insert(toml_key, RawValue { value, source });
+        }
+
+        fn describe(&self, toml_key: &str) -> String {
+            let env_key = SETTINGS.iter().find(|(key, _)| *key == toml_key).map(|(_, env_key)| *env_key).unwrap_or("");
+            match self.values.get(toml_key) {
+                Some(raw) => format!("{} / {} (from {})", toml_key, env_key, raw.source),
+                None => format!("{} / {}", toml_key, env_key),
+            }
+        }
+
+        fn get_string(&self, toml_key: &str, default: &str) -> String {
+            self.values.get(toml_key).map(|raw| raw.value.clone()).unwrap_or_else(|| String::from(default))
+        }
+
+        // secrets are left unset rather than defaulted, an empty one counts as unset
+        fn get_secret(&self, toml_key: &str) -> Option<String> {
+            self.values.get(toml_key).map(|raw| raw.value.clone()).filter(|value| !value.is_empty())
+        }
+
+        // counts, capacities and timeouts, all of which must be at least 1
+        fn get_positive(&mut self, toml_key: &str, default: usize) -> usize {
+            let parsed = match self.values.get(toml_key) {
+                None => return default,
+                Some(raw) => raw.value.trim().parse::<usize>(),
+            };
+            match parsed {
+                Ok(value) if value > 0 => value,
+                _ => {
+                    let message = format!(
+                        "{}: '{}' is not a whole number greater than 0",
+                        self.describe(toml_key),
+                        self.values[toml_key].value
+                    );
+                    self.errors.push(message);
+                    default
+                }
+            }
+        }
+
+        // a key that isn't in the hashed form is refused outright, rather than compared as plain text
+        fn get_hashed_key(&mut self, toml_key: &str) -> Option<HashedKey> {
+            let stored = self.get_secret(toml_key)?;
+            match HashedKey::parse(&stored) {
+                Ok(hashed_key) => Some(hashed_key),
+                Err(message) => {
+                    let message = format!(
+                        "{}: {}, run `couch-gag-metrics-hub hash-key` to hash a key",
+                        self.describe(toml_key),
+                        message
+                    );
+                    self.errors.push(message);
+                    None
+                }
+            }
+        }
+
+        // left unset rather than defaulted, a value that is there must still be at least 1
+        fn get_optional_positive(&mut self, toml_key: &str) -> Option<usize> {
+            if self.values.contains_key(toml_key) {
+                Some(self.get_positive(toml_key, 0))
+            } else {
+                None
+            }
+        }
+
+        fn get_bool(&mut self, toml_key: &str, default: bool) -> bool {
+            let value = match self.values.get(toml_key) {
+                None => return default,
+                Some(raw) => raw.value.trim().to_ascii_lowercase(),
+            };
+            match value.as_str() {
+                "true" | "1" | "yes" => true,
+                "false" | "0" | "no" => false,
+                _ => {
+                    let message = format!("{}: '{}' is not true or false", self.describe(toml_key), value);
+                    self.errors.push(message);
+                    default
+                }
+            }
+        }
+    }
+
+    impl HubConfig {
+        // Reads every source and checks the result. Err holds every problem found, not just the first.
+        pub fn load(toml_path: &Path, dotenv_path: &Path) -> Result<HubConfig, Vec<String>> {
+            let mut raw = read_sources(toml_path, dotenv_path);
+
+            let config = HubConfig {
+                ulysses_hashed_key: raw.get_hashed_key("ulysses_hashed_key"),
+                ulysses_previous_hashed_key: raw.get_hashed_key("ulysses_previous_hashed_key"),
+                ulysses_previous_key_valid_until: raw.get_optional_positive("ulysses_previous_key_valid_until").map(|secs| secs as u64),
+                ulysses_key_pepper: raw.get_secret("ulysses_key_pepper"),
+                api_keys_path: raw.get_string("api_keys_path", "config/api_keys.json"),
+                beacon_secret: raw.get_secret("beacon_secret"),
+                beacon_token_ttl_secs: raw.get_positive("beacon_token_ttl_secs", 24 * 60 * 60) as u64,
+                beacon_token_max_ttl_secs: raw.get_positive("beacon_token_max_ttl_secs", 90 * 24 * 60 * 60) as u64,
+                metrics_log_path: raw.get_string("metrics_log_path", "data/metrics.log"),
+                metric_registry_path: raw.get_string("metric_registry_path", "config/metric_types.json"),
+                strict_metric_types: raw.get_bool("strict_metric_types", false),
+                worker_count: raw.get_positive("worker_count", 4),
+                queue_depth: raw.get_positive("queue_depth", 64),
+                keep_alive_idle_secs: raw.get_positive("keep_alive_idle_secs", 5) as u64,
+                keep_alive_max_requests: raw.get_positive("keep_alive_max_requests", 100),
+                shutdown_grace_secs: raw.get_positive("shutdown_grace_secs", 8) as u64,
+                quarantine_capacity: raw.get_positive("quarantine_capacity", 1000),
+                query_window_size: raw.get_positive("query_window_size", 100_000),
+                prometheus_max_targets: raw.get_positive("prometheus_max_targets", 200),
+                prometheus_max_tag_keys: raw.get_positive("prometheus_max_tag_keys", 20),
+                prometheus_max_tag_values: raw.get_positive("prometheus_max_tag_values", 50),
+                prometheus_max_series: raw.get_positive("prometheus_max_series", 5000),
+            };
+
+            let mut errors = raw.errors;
+            if config.beacon_token_ttl_secs > config.beacon_token_max_ttl_secs {
+                errors.push(format!(
+                    "beacon_token_ttl_secs: {} is longer than beacon_token_max_ttl_secs ({})",
+                    config.beacon_token_ttl_secs, config.beacon_token_max_ttl_secs
+                ));
+            }
+            // a previous key without a cutoff would never stop being accepted, which is no rotation at all
+            if config.ulysses_previous_hashed_key.is_some() != config.ulysses_previous_key_valid_until.is_some() {
+                errors.push(String::from(
+                    "ulysses_previous_hashed_key: must be set together with ulysses_previous_key_valid_until",
+                ));
+            }
+            if config.ulysses_previous_hashed_key.is_some() && config.ulysses_hashed_key.is_none() {
+                errors.push(String::from("ulysses_previous_hashed_key: is set but ulysses_hashed_key isn't"));
+            }
+            if config.metrics_log_path.trim().is_empty() {
+                errors.push(String::from("metrics_log_path: must not be empty"));
+            }
+
+            if errors.is_empty() {
+                Ok(config)
+            } else {
+                Err(errors)
+            }
+        }
+
+        // the settings a reload can't apply, those that differ between self and the reloaded config
+        pub fn get_restart_only_changes(&self, reloaded: &HubConfig) -> Vec<&'static str> {
+            let mut changed = Vec::new();
+            let mut check = |name: &'static str, differs: bool| {
+                if differs {
+                    changed.push(name);
+                }
+            };
+
+            check("metrics_log_path", self.metrics_log_path != reloaded.metrics_log_path);
+            check("metric_registry_path", self.metric_registry_path != reloaded.metric_registry_path);
+            check("strict_metric_types", self.strict_metric_types != reloaded.strict_metric_types);
+            check("worker_count", self.worker_count != reloaded.worker_count);
+            check("queue_depth", self.queue_depth != reloaded.queue_depth);
+            check("keep_alive_idle_secs", self.keep_alive_idle_secs != reloaded.keep_alive_idle_secs);
+            check("keep_alive_max_requests", self.keep_alive_max_requests != reloaded.keep_alive_max_requests);
+            check("shutdown_grace_secs", self.shutdown_grace_secs != reloaded.shutdown_grace_secs);
+            check("quarantine_capacity", self.quarantine_capacity != reloaded.quarantine_capacity);
+            check("query_window_size", self.query_window_size != reloaded.query_window_size);
+            check("prometheus_max_targets", self.prometheus_max_targets != reloaded.prometheus_max_targets);
+            check("prometheus_max_tag_keys", self.prometheus_max_tag_keys != reloaded.prometheus_max_tag_keys);
+            check("prometheus_max_tag_values", self.prometheus_max_tag_values != reloaded.prometheus_max_tag_values);
+            check("prometheus_max_series", self.prometheus_max_series != reloaded.prometheus_max_series);
+            changed
+        }
+    }
+
+    // A single setting's value as the sources have it, without checking anything else.
+    // For `hash-key`, which needs the pepper before there is a key the full config would accept.
+    pub fn read_secret(toml_path: &Path, dotenv_path: &Path, toml_key: &str) -> Result<Option<String>, Vec<String>> {
+        let raw = read_sources(toml_path, dotenv_path);
+        if raw.errors.is_empty() {
+            Ok(raw.get_secret(toml_key))
+        } else {
+            Err(raw.errors)
+        }
+    }
+
+    fn read_sources(toml_path: &Path, dotenv_path: &Path) -> RawSettings {
+        let mut raw = RawSettings {
+            values: HashMap::new(),
+            errors: Vec::new(),
+        };
+
+        read_toml_file(toml_path, &mut raw);
+        read_dotenv_file(dotenv_path, &mut raw);
+        for (toml_key, env_key) in SETTINGS.iter() {
+            if let Ok(value) = std::env::var(env_key) {
+                if !value.is_empty() {
+                    raw.set(toml_key, value, ConfigSource::Environment);
+                }
+            }
+        }
+        raw
+    }
+
+    // A missing file just means nothing is configured there. Values may be strings, integers or booleans.
+    fn read_toml_file(path: &Path, raw: &mut RawSettings) {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return,
+            Err(e) => {
+                raw.errors.push(format!("could not read {} ({})", path.display(), e));
+                return;
+            }
+        };
+
+        let table = match toml::from_str::<toml::value::Table>(&contents) {
+            Ok(table) => table,
+            Err(e) => {
+                raw.errors.push(format!("{} is not valid TOML ({})", path.display(), e.to_string().trim()));
+                return;
+            }
+        };
+
+        for (key, value) in table.into_iter() {
+            let toml_key = match SETTINGS.iter().find(|(toml_key, _)| *toml_key == key) {
+                Some((toml_key, _)) => *toml_key,
+                None => {
+                    raw.errors.push(format!("{}: '{}' is not a setting", path.display(), key));
+                    continue;
+                }
+            };
+
+            let value = match value {
+                toml::Value::String(value) => value,
+                toml::Value::Integer(value) => value.to_string(),
+                toml::Value::Boolean(value) => value.to_string(),
+                other => {
+                    raw.errors.push(format!(
+                        "{}: {} must be a string, integer or boolean, not {}",
+                        path.display(),
+                        key,
+                        other.type_str()
+                    ));
+                    continue;
+                }
+            };
+            raw.set(toml_key, value, ConfigSource::TomlFile);
+        }
+    }
+
+    // anything in .env that isn't a setting is left for other tools
+    fn read_dotenv_file(path: &Path, raw: &mut RawSettings) {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return,
+            Err(e) => {
+                raw.errors.push(format!("could not read {} ({})", path.display(), e));
+                return;
+            }
+        };
+
+        let values = match dotenv::parse(&contents) {
+            Ok(values) => values,
+            Err(errors) => {
+                for error in errors {
+                    raw.errors.push(format!("{} {}", path.display(), error));
+                }
+                return;
+            }
+        };
+
+        for (key, value) in values {
+            if let Some((toml_key, _)) = SETTINGS.iter().find(|(_, env_key)| *env_key == key) {
+                raw.set(toml_key, value, ConfigSource::DotEnv);
+            }
+        }
+    }
+}
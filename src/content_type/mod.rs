@@ -5,11 +5,16 @@ pub mod content_type_base_kit {
 
     impl ContentHeaders {
         pub fn add_content_type_to_headers(headers: &mut HashMap<String, String>){
+            Self::add_content_type_to_headers_as(headers, "application/json");
+        }
+
+        // for routes that don't answer with JSON, e.g. "image/gif"
+        pub fn add_content_type_to_headers_as(headers: &mut HashMap<String, String>, content_type: &str) {
             headers.insert(
                 String::from("Content-Type"),
-                String::from("application/json"),
+                String::from(content_type),
             );
-        }  
+        }
     }
    
 }
\ No newline at end of file
@@ -15,7 +15,7 @@ pub mod cors_base_kit {
             );
             headers.insert(
                 String::from("Access-Control-Allow-Methods"),
-                String::from("OPTIONS, GET"),
+                String::from("OPTIONS, GET, POST"),
             );
             headers.insert(
                 String::from("Access-Control-Allow-Headers"),
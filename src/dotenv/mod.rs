@@ -0,0 +1,297 @@
+pub mod dotenv {
+
+    // Reads .env files, in the dialect most dotenv tools agree on:
+    //
+    //   # a comment                       whole line comments, and " #" after an unquoted value
+    //   export KEY=value                  the export prefix is ignored, so the file can be sourced by a shell
+    //   KEY=a=b==                         everything after the first = is the value, padding and all
+    //   KEY='literal ${NOT} \n'           single quotes keep the value exactly as written
+    //   KEY="line\nbreak ${OTHER}"        double quotes take \n \r \t \" \\ \$ escapes, and may span lines
+    //   KEY=${OTHER}/path                 ${NAME} (or ${NAME:-fallback}) in unquoted and double quoted values
+    //
+    // ${NAME} is looked up in the process environment, then among the keys above it in the file.
+
+    use std::fmt;
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct DotEnvError {
+        // 1 based, the line the entry starts on
+        pub line: usize,
+        pub message: String,
+    }
+
+    impl fmt::Display for DotEnvError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "line {}: {}", self.line, self.message)
+        }
+    }
+
+    // Every KEY=value in the file, in order. A key set twice appears twice, the last one is the one that counts.
+    // Every bad line is reported, not just the first.
+    pub fn parse(contents: &str) -> Result<Vec<(String, String)>, Vec<DotEnvError>> {
+        let lines: Vec<&str> = contents.lines().collect();
+        let mut values: Vec<(String, String)> = Vec::new();
+        let mut errors: Vec<DotEnvError> = Vec::new();
+        let mut index = 0;
+
+        while index < lines.len() {
+            let line_number = index + 1;
+            let line = lines[index].trim();
+            index += 1;
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut error = |message: String| errors.push(DotEnvError { line: line_number, message });
+
+            let line = match line.strip_prefix("export") {
+                Some(rest) if rest.starts_with(char::is_whitespace) => rest.trim_start(),
+                _ => line,
+            };
+
+            let (key, rest) = match line.split_once('=') {
+                Some((key, rest)) => (key.trim(), rest.trim_start()),
+                None => {
+                    error(String::from("expected KEY=value"));
+                    continue;
+                }
+            };
+            if !is_valid_key(key) {
+                error(format!("'{}' is not a valid key, use letters, digits, '_' and '.', starting with a letter or '_'", key));
+                continue;
+            }
+
+            let lookup = |name: &str| -> Option<String> {
+                std::env::var(name)
+                    .ok()
+                    .or_else(|| values.iter().rev().find(|(key, _)| key == name).map(|(_, value)| value.clone()))
+            };
+
+            let value = if let Some(quoted) = rest.strip_prefix('"') {
+                // keep pulling in lines until the quote closes
+                let mut raw = String::from(quoted);
+                let closing = loop {
+                    if let Some(closing) = find_closing_double_quote(&raw) {
+                        break Some(closing);
+                    }
+                    if index >= lines.len() {
+                        break None;
+                    }
+                    raw.push('\n');
+                    raw.push_str(lines[index]);
+                    index += 1;
+                };
+
+                match closing {
+                    None => Err(String::from("double quoted value is never closed")),
+                    Some(closing) => check_after_quote(&raw[closing + 1..]).and_then(|_| unescape_and_interpolate(&raw[..closing], &lookup)),
+                }
+            } else if let Some(quoted) = rest.strip_prefix('\'') {
+                match quoted.find('\'') {
+                    None => Err(String::from("single quoted value is never closed")),
+                    Some(closing) => check_after_quote(&quoted[closing + 1..]).map(|_| String::from(&quoted[..closing])),
+                }
+            } else {
+                interpolate_unquoted(strip_inline_comment(rest).trim_end(), &lookup)
+            };
+
+            match value {
+                Ok(value) => values.push((String::from(key), value)),
+                Err(message) => error(format!("{}: {}", key, message)),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(values)
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn is_valid_key(key: &str) -> bool {
+        let mut chars = key.chars();
+        match chars.next() {
+            Some(first) if first.is_ascii_alphabetic() || first == '_' => {}
+            _ => return false,
+        }
+        chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+    }
+
+    // only whitespace or a comment may follow a closing quote
+    fn check_after_quote(trailing: &str) -> Result<(), String> {
+        let trailing = trailing.trim();
+        if trailing.is_empty() || trailing.starts_with('#') {
+            Ok(())
+        } else {
+            Err(format!("unexpected '{}' after the closing quote", trailing))
+        }
+    }
+
+    // the byte index of the first quote that isn't escaped
+    fn find_closing_double_quote(raw: &str) -> Option<usize> {
+        let mut escaped = false;
+        for (position, c) in raw.char_indices() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => return Some(position),
+                _ => {}
+            }
+        }
+        None
+    }
+
+    // an unquoted value ends where " #" starts a comment, a # with no space before it is part of the value
+    fn strip_inline_comment(value: &str) -> &str {
+        let mut previous_is_space = false;
+        for (position, c) in value.char_indices() {
+            if c == '#' && previous_is_space {
+                return &value[..position];
+            }
+            previous_is_space = c.is_whitespace();
+        }
+        value
+    }
+
+    fn unescape_and_interpolate(raw: &str, lookup: &dyn Fn(&str) -> Option<String>) -> Result<String, String> {
+        let mut value = String::with_capacity(raw.len());
+        let mut chars = raw.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some('n') => value.push('\n'),
+                    Some('r') => value.push('\r'),
+                    Some('t') => value.push('\t'),
+                    Some('"') => value.push('"'),
+                    Some('\\') => value.push('\\'),
+                    Some('$') => value.push('$'),
+                    // not an escape we know, keep it as written
+                    Some(other) => {
+                        value.push('\\');
+                        value.push(other);
+                    }
+                    None => value.push('\\'),
+                },
+                '$' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    value.push_str(&read_interpolation(&mut chars, lookup)?);
+                }
+                _ => value.push(c),
+            }
+        }
+        Ok(value)
+    }
+
+    fn interpolate_unquoted(raw: &str, lookup: &dyn Fn(&str) -> Option<String>) -> Result<String, String> {
+        let mut value = String::with_capacity(raw.len());
+        let mut chars = raw.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            if c == '$' && chars.peek() == Some(&'{') {
+                chars.next();
+                value.push_str(&read_interpolation(&mut chars, lookup)?);
+            } else {
+                value.push(c);
+            }
+        }
+        Ok(value)
+    }
+
+    // reads NAME} or NAME:-fallback} (the "${" already taken) and resolves it
+    fn read_interpolation(
+        chars: &mut std::iter::Peekable<std::str::Chars>,
+        lookup: &dyn Fn(&str) -> Option<String>,
+    ) -> Result<String, String> {
+        let mut reference = String::new();
+        loop {
+            match chars.next() {
+                Some('}') => break,
+                Some(c) => reference.push(c),
+                None => return Err(format!("'${{{}' is never closed with '}}'", reference)),
+            }
+        }
+
+        let (name, fallback) = match reference.split_once(":-") {
+            Some((name, fallback)) => (name, Some(fallback)),
+            None => (reference.as_str(), None),
+        };
+        if !is_valid_key(name) {
+            return Err(format!("'${{{}}}' does not name a variable", reference));
+        }
+
+        match (lookup(name).filter(|value| !value.is_empty()), fallback) {
+            (Some(value), _) => Ok(value),
+            (None, Some(fallback)) => Ok(String::from(fallback)),
+            (None, None) => Err(format!("'${{{}}}' is not set, use ${{{}:-<fallback>}} if it may be missing", name, name)),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn get<'v>(values: &'v [(String, String)], key: &str) -> &'v str {
+            values.iter().rev().find(|(k, _)| k == key).map(|(_, value)| value.as_str()).unwrap()
+        }
+
+        #[test]
+        fn keeps_everything_after_the_first_equals_sign() {
+            let values = parse("TOKEN=a=b==\nURL=http://hub/?x=1&y=2").unwrap();
+            assert_eq!(get(&values, "TOKEN"), "a=b==");
+            assert_eq!(get(&values, "URL"), "http://hub/?x=1&y=2");
+        }
+
+        #[test]
+        fn ignores_the_export_prefix() {
+            let values = parse("export HUB_WORKER_COUNT=8\nexport\tQUOTED=\"x\"\nexporter=y").unwrap();
+            assert_eq!(get(&values, "HUB_WORKER_COUNT"), "8");
+            assert_eq!(get(&values, "QUOTED"), "x");
+            // only a separate word counts as the prefix
+            assert_eq!(get(&values, "exporter"), "y");
+        }
+
+        #[test]
+        fn reports_unterminated_quotes_on_the_line_they_start() {
+            // double quotes may span lines, so an unclosed one runs to the end of the file
+            let errors = parse("A=1\nB=\"never closed\nC=2").unwrap_err();
+            assert_eq!(errors, vec![DotEnvError { line: 2, message: String::from("B: double quoted value is never closed") }]);
+
+            let errors = parse("A=1\nB='never closed\nC='also never").unwrap_err();
+            assert_eq!(errors.len(), 2);
+            assert_eq!(errors[0], DotEnvError { line: 2, message: String::from("B: single quoted value is never closed") });
+            assert_eq!(errors[1].line, 3);
+        }
+
+        #[test]
+        fn interpolates_with_a_fallback_for_unset_names() {
+            let contents = "BASE=/data\n\
+                            LOG=${BASE}/metrics.log\n\
+                            MISSING=${COUCH_GAG_DOTENV_TEST_UNSET:-fallback}\n\
+                            SET=${BASE:-unused}\n\
+                            QUOTED=\"${COUCH_GAG_DOTENV_TEST_UNSET:-a b}\"\n\
+                            LITERAL='${BASE}'";
+            let values = parse(contents).unwrap();
+            assert_eq!(get(&values, "LOG"), "/data/metrics.log");
+            assert_eq!(get(&values, "MISSING"), "fallback");
+            assert_eq!(get(&values, "SET"), "/data");
+            assert_eq!(get(&values, "QUOTED"), "a b");
+            assert_eq!(get(&values, "LITERAL"), "${BASE}");
+
+            let errors = parse("X=${COUCH_GAG_DOTENV_TEST_UNSET}").unwrap_err();
+            assert_eq!(errors[0].line, 1);
+        }
+
+        #[test]
+        fn accepts_dots_in_keys_and_says_so_when_a_key_is_invalid() {
+            assert_eq!(get(&parse("hub.worker_count=4").unwrap(), "hub.worker_count"), "4");
+
+            let errors = parse("1KEY=x").unwrap_err();
+            assert_eq!(
+                errors[0].message,
+                "'1KEY' is not a valid key, use letters, digits, '_' and '.', starting with a letter or '_'"
+            );
+        }
+    }
+}
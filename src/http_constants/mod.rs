@@ -1,10 +1,66 @@
 pub mod http_base_kit {
 
-    const STATUS_200_RESPONSE_PREFIX: &str = "HTTP/1.1 200 OK";
-    const STATUS_500_RESPONSE_PREFIX: &str = "HTTP/1.1 500 INTERNAL SERVER ERROR";
+    const HTTP_VERSION: &str = "HTTP/1.1";
 
     pub mod http_constants {
-        use super::{STATUS_200_RESPONSE_PREFIX, STATUS_500_RESPONSE_PREFIX};
+        use super::HTTP_VERSION;
+
+        // every status the hub answers with, see get_reason_phrase for what each one means to us
+        #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+        pub enum StatusCode {
+            Ok,
+            BadRequest,
+            Unauthorized,
+            Forbidden,
+            NotFound,
+            MethodNotAllowed,
+            PayloadTooLarge,
+            UnsupportedMediaType,
+            UnprocessableEntity,
+            TooManyRequests,
+            RequestHeaderFieldsTooLarge,
+            InternalServerError,
+            ServiceUnavailable,
+        }
+
+        impl StatusCode {
+            pub fn get_code(&self) -> usize {
+                match self {
+                    StatusCode::Ok => 200,
+                    StatusCode::BadRequest => 400,
+                    StatusCode::Unauthorized => 401,
+                    StatusCode::Forbidden => 403,
+                    StatusCode::NotFound => 404,
+                    StatusCode::MethodNotAllowed => 405,
+                    StatusCode::PayloadTooLarge => 413,
+                    StatusCode::UnsupportedMediaType => 415,
+                    StatusCode::UnprocessableEntity => 422,
+                    StatusCode::TooManyRequests => 429,
+                    StatusCode::RequestHeaderFieldsTooLarge => 431,
+                    StatusCode::InternalServerError => 500,
+                    StatusCode::ServiceUnavailable => 503,
+                }
+            }
+
+            // the reason phrases from rfc9110 section 15
+            pub fn get_reason_phrase(&self) -> &'static str {
+                match self {
+                    StatusCode::Ok => "OK",
+                    StatusCode::BadRequest => "Bad Request",
+                    StatusCode::Unauthorized => "Unauthorized",
+                    StatusCode::Forbidden => "Forbidden",
+                    StatusCode::NotFound => "Not Found",
+                    StatusCode::MethodNotAllowed => "Method Not Allowed",
+                    StatusCode::PayloadTooLarge => "Content Too Large",
+                    StatusCode::UnsupportedMediaType => "Unsupported Media Type",
+                    StatusCode::UnprocessableEntity => "Unprocessable Content",
+                    StatusCode::TooManyRequests => "Too Many Requests",
+                    StatusCode::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+                    StatusCode::InternalServerError => "Internal Server Error",
+                    StatusCode::ServiceUnavailable => "Service Unavailable",
+                }
+            }
+        }
 
         pub struct HttpConstants {}
 
@@ -12,16 +68,10 @@ pub mod http_base_kit {
             pub fn get_crlf() -> &'static str {
                 "\r\n"
             }
-    
-            pub fn get_success_get_protocol_http_prefix() -> String {
-                let mut http_head = String::from(STATUS_200_RESPONSE_PREFIX);
-                let crlf = Self::get_crlf();
-                http_head.push_str(crlf);
-                http_head
-            }
-    
-            pub fn get_error_server_internal_protocol_http_prefix() -> String {
-                let mut http_head = String::from(STATUS_500_RESPONSE_PREFIX);
+
+            // "HTTP/1.1 404 Not Found\r\n"
+            pub fn get_status_line(status: StatusCode) -> String {
+                let mut http_head = format!("{} {} {}", HTTP_VERSION, status.get_code(), status.get_reason_phrase());
                 let crlf = Self::get_crlf();
                 http_head.push_str(crlf);
                 http_head
@@ -1,29 +1,41 @@
 pub mod http_request_base_kit {
 
-    use crate::http_constants::http_base_kit::http_constants::HttpConstants;
-    use std::{borrow::Cow, collections::HashMap};
+    use std::collections::HashMap;
+    use std::fmt;
+    use std::io::prelude::*;
+    use std::io::ErrorKind;
+
+    // Guards against clients that never stop sending.
+    // Anything past these limits is refused rather than buffered.
+    const MAX_REQUEST_HEAD_BYTES: usize = 16 * 1024;
+    const MAX_HEADER_COUNT: usize = 100;
+    const MAX_BODY_BYTES: usize = 1024 * 1024;
 
     #[derive(Serialize, Deserialize, Debug)]
     pub struct HttpRequest {
         pub method: String,
+        // header names are stored lowercased, HTTP header names are case-insensitive
         pub headers: HashMap<String, String>,
         pub path: String,
+        pub version: String,
         pub data: String,
+        // the peer's socket address, filled in by the connection handler once the request is read
+        #[serde(default)]
+        pub remote_addr: Option<String>,
     }
 
     impl HttpRequest {
         pub fn get_header_by_key(&self, key: String) -> String {
-            let header_option = self.headers.get(&key);
-            let header_value = match header_option {
+            let header_option = self.headers.get(&key.to_ascii_lowercase());
+            match header_option {
                 Some(header_val) => header_val.clone(),
                 None => String::new(),
-            };
-            header_value
+            }
         }
 
         pub fn get_path(&self) -> String {
-            let path_vec: Vec<&str> = self.path.split("?").collect(); // query parameter delimiter
-            let sanitized_path_option = path_vec.get(0);
+            let path_vec: Vec<&str> = self.path.split('?').collect(); // query parameter delimiter
+            let sanitized_path_option = path_vec.first();
             let sanitized_path = match sanitized_path_option {
                 Some(clean_path) => *clean_path,
                 None => "/"
@@ -32,33 +44,28 @@ pub mod http_request_base_kit {
         }
 
         pub fn get_query_parameter(&self, query_key: &str) -> Option<String> {
-            let path_vec: Vec<&str> = self.path.split("?").collect();
+            let path_vec: Vec<&str> = self.path.split('?').collect();
             let query_param_vec_option = path_vec.get(1);
             let query_param_vec_string = match query_param_vec_option {
                 Some(s) => *s,
                 None => ""
             };
 
-            if query_param_vec_string == "" {
+            if query_param_vec_string.is_empty() {
                 return None
             }
 
             let mut query_params: HashMap<String, String> = HashMap::new();
-            let query_param_vec: Vec<&str> = query_param_vec_string.split("&").collect();
+            let query_param_vec: Vec<&str> = query_param_vec_string.split('&').collect();
             for key_value_string in query_param_vec.iter() {
-                let key_value_vec: Vec<&str> = key_value_string.split("=").collect();
-                let key = *key_value_vec.get(0).unwrap_or(&"");
-                let value = *key_value_vec.get(1).unwrap_or(&"");   
-                if key != "" && value != "" {
+                let key_value_vec: Vec<&str> = key_value_string.split('=').collect();
+                let key = *key_value_vec.first().unwrap_or(&"");
+                let value = *key_value_vec.get(1).unwrap_or(&"");
+                if !key.is_empty() && !value.is_empty() {
                     query_params.insert(String::from(key), String::from(value));
                 }
             };
-            let val_o = query_params.get(query_key);
-
-            match val_o {
-                Some(s) => Some(s.clone()),
-                None => None
-            }
+            query_params.get(query_key).cloned()
         }
 
         pub fn get_http_method(&self) -> String {
@@ -70,81 +77,368 @@ pub mod http_request_base_kit {
         }
     }
 
-    pub fn parse_http_request_from_buffer(req_buffer: &Cow<str>) -> HttpRequest {
-        let http_request_array: Vec<&str> = req_buffer.split("\n").collect(); // Get the request as a vec of newline separated strings
-        let protocol_line_option = http_request_array.get(0); // the lead string (index 0) will contain http-protocol method and path (GET / HTTP/1.1)
-        let protocol_line = match protocol_line_option {
-            Some(req_string_protocol_line) => req_string_protocol_line,
-            None => "GET / HTTP/1.1",
-        };
+    #[derive(Debug)]
+    pub enum HttpParseError {
+        // the client closed the connection before sending a single byte
+        ConnectionClosed,
+        // the client closed the connection part way through a request
+        UnexpectedEof,
+        Io(std::io::Error),
+        MalformedRequestLine(String),
+        UnsupportedVersion(String),
+        MalformedHeader(String),
+        HeadersTooLarge,
+        InvalidContentLength(String),
+        // both Content-Length and Transfer-Encoding were sent
+        AmbiguousBodyLength,
+        UnsupportedTransferEncoding(String),
+        InvalidChunk(String),
+        BodyTooLarge,
+    }
+
+    impl fmt::Display for HttpParseError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                HttpParseError::ConnectionClosed => write!(f, "connection closed before a request was sent"),
+                HttpParseError::UnexpectedEof => write!(f, "connection closed part way through the request"),
+                HttpParseError::Io(e) => write!(f, "failed to read request ({})", e),
+                HttpParseError::MalformedRequestLine(line) => write!(f, "malformed request line '{}'", line),
+                HttpParseError::UnsupportedVersion(version) => write!(f, "unsupported http version '{}'", version),
+                HttpParseError::MalformedHeader(line) => write!(f, "malformed header '{}'", line),
+                HttpParseError::HeadersTooLarge => write!(
+                    f,
+                    "request head exceeds {} bytes or {} headers",
+                    MAX_REQUEST_HEAD_BYTES, MAX_HEADER_COUNT
+                ),
+                HttpParseError::InvalidContentLength(value) => write!(f, "invalid Content-Length '{}'", value),
+                HttpParseError::AmbiguousBodyLength => {
+                    write!(f, "both Content-Length and Transfer-Encoding were sent")
+                }
+                HttpParseError::UnsupportedTransferEncoding(value) => {
+                    write!(f, "unsupported Transfer-Encoding '{}'", value)
+                }
+                HttpParseError::InvalidChunk(reason) => write!(f, "invalid chunked body ({})", reason),
+                HttpParseError::BodyTooLarge => write!(f, "request body exceeds {} bytes", MAX_BODY_BYTES),
+            }
+        }
+    }
+
+    impl HttpParseError {
+        // a short fixed name for the kind of failure, for counting them without the client's input
+        pub fn get_reason(&self) -> &'static str {
+            match self {
+                HttpParseError::ConnectionClosed => "connection_closed",
+                HttpParseError::UnexpectedEof => "unexpected_eof",
+                HttpParseError::Io(_) => "io",
+                HttpParseError::MalformedRequestLine(_) => "malformed_request_line",
+                HttpParseError::UnsupportedVersion(_) => "unsupported_version",
+                HttpParseError::MalformedHeader(_) => "malformed_header",
+                HttpParseError::HeadersTooLarge => "headers_too_large",
+                HttpParseError::InvalidContentLength(_) => "invalid_content_length",
+                HttpParseError::AmbiguousBodyLength => "ambiguous_body_length",
+                HttpParseError::UnsupportedTransferEncoding(_) => "unsupported_transfer_encoding",
+                HttpParseError::InvalidChunk(_) => "invalid_chunk",
+                HttpParseError::BodyTooLarge => "body_too_large",
+            }
+        }
+    }
+
+    impl From<std::io::Error> for HttpParseError {
+        fn from(e: std::io::Error) -> HttpParseError {
+            if e.kind() == ErrorKind::UnexpectedEof {
+                HttpParseError::UnexpectedEof
+            } else {
+                HttpParseError::Io(e)
+            }
+        }
+    }
+
+    // Reads exactly one request off the reader, leaving anything after it unread.
+    //
+    // The head (request line + headers) is read line by line up to the blank line,
+    // then the body is read according to Transfer-Encoding or Content-Length.
+    // https://www.rfc-editor.org/rfc/rfc9112
+    pub fn parse_http_request<R: BufRead>(reader: &mut R) -> Result<HttpRequest, HttpParseError> {
+        let mut head_budget = MAX_REQUEST_HEAD_BYTES;
+
+        // servers should ignore empty lines ahead of the request line (rfc9112 2.2)
+        let mut request_line = String::new();
+        let mut is_first_line = true;
+        while request_line.is_empty() {
+            request_line = match read_line(reader, &mut head_budget)? {
+                Some(line) => line,
+                None if is_first_line => return Err(HttpParseError::ConnectionClosed),
+                None => return Err(HttpParseError::UnexpectedEof),
+            };
+            is_first_line = false;
+        }
 
-        let protocol_line_split_on_whitespace: Vec<&str> = protocol_line.split(" ").collect();
+        let (method, path, version) = parse_request_line(&request_line)?;
 
-        let http_req_method_option = protocol_line_split_on_whitespace.get(0);
-        let http_req_method = match http_req_method_option {
-            Some(method) => *method,
-            None => "GET",
+        let mut headers: HashMap<String, String> = HashMap::new();
+        let mut header_count = 0;
+        loop {
+            let line = match read_line(reader, &mut head_budget)? {
+                Some(line) => line,
+                None => return Err(HttpParseError::UnexpectedEof),
+            };
+            if line.is_empty() {
+                break;
+            }
+
+            header_count += 1;
+            if header_count > MAX_HEADER_COUNT {
+                return Err(HttpParseError::HeadersTooLarge);
+            }
+
+            let (key, value) = parse_header_line(&line)?;
+            // repeated headers are folded into one comma separated value (rfc9110 5.3)
+            headers
+                .entry(key)
+                .and_modify(|existing| {
+                    existing.push_str(", ");
+                    existing.push_str(&value);
+                })
+                .or_insert(value);
+        }
+
+        let body = read_body(reader, &headers)?;
+
+        Ok(HttpRequest {
+            method,
+            path,
+            version,
+            headers,
+            data: String::from_utf8_lossy(&body).into_owned(),
+            remote_addr: None,
+        })
+    }
+
+    // Reads a single line, without its line ending.
+    // Returns None if the reader is already at EOF.
+    fn read_line<R: BufRead>(reader: &mut R, budget: &mut usize) -> Result<Option<String>, HttpParseError> {
+        let mut line: Vec<u8> = Vec::new();
+        let bytes_read = reader.by_ref().take(*budget as u64 + 1).read_until(b'\n', &mut line)?;
+
+        if bytes_read == 0 {
+            return Ok(None);
+        }
+        if bytes_read > *budget {
+            return Err(HttpParseError::HeadersTooLarge);
+        }
+        *budget -= bytes_read;
+
+        if line.last() != Some(&b'\n') {
+            return Err(HttpParseError::UnexpectedEof);
+        }
+        line.pop();
+        // bare \n line endings are tolerated (rfc9112 2.2)
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+
+        Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+    }
+
+    fn parse_request_line(line: &str) -> Result<(String, String, String), HttpParseError> {
+        let parts: Vec<&str> = line.split(' ').collect();
+        if parts.len() != 3 || parts.iter().any(|part| part.is_empty()) {
+            return Err(HttpParseError::MalformedRequestLine(String::from(line)));
+        }
+
+        let method = parts[0];
+        if !method.bytes().all(is_token_byte) {
+            return Err(HttpParseError::MalformedRequestLine(String::from(line)));
+        }
+
+        let version = parts[2];
+        if version != "HTTP/1.1" && version != "HTTP/1.0" {
+            return Err(HttpParseError::UnsupportedVersion(String::from(version)));
+        }
+
+        Ok((String::from(method), String::from(parts[1]), String::from(version)))
+    }
+
+    fn parse_header_line(line: &str) -> Result<(String, String), HttpParseError> {
+        // obsolete line folding (a header continued on a line starting with whitespace) is rejected (rfc9112 5.2)
+        let colon_index = match line.find(':') {
+            Some(index) if index > 0 => index,
+            _ => return Err(HttpParseError::MalformedHeader(String::from(line))),
         };
 
-        let http_req_path_option = protocol_line_split_on_whitespace.get(1);
-        let http_req_path = match http_req_path_option {
-            Some(path) => {
-                *path
-            },
-            None => "/",
+        let key = &line[..colon_index];
+        if !key.bytes().all(is_token_byte) {
+            return Err(HttpParseError::MalformedHeader(String::from(line)));
+        }
+
+        let value = line[colon_index + 1..].trim_matches(|c| c == ' ' || c == '\t');
+        Ok((key.to_ascii_lowercase(), String::from(value)))
+    }
+
+    fn read_body<R: BufRead>(reader: &mut R, headers: &HashMap<String, String>) -> Result<Vec<u8>, HttpParseError> {
+        let transfer_encoding = headers.get("transfer-encoding");
+        let content_length = headers.get("content-length");
+
+        if let Some(transfer_encoding) = transfer_encoding {
+            // a request carrying both is a classic request smuggling vector, refuse it outright
+            if content_length.is_some() {
+                return Err(HttpParseError::AmbiguousBodyLength);
+            }
+            if !transfer_encoding.eq_ignore_ascii_case("chunked") {
+                return Err(HttpParseError::UnsupportedTransferEncoding(transfer_encoding.clone()));
+            }
+            return read_chunked_body(reader);
+        }
+
+        let length = match content_length {
+            Some(value) => parse_content_length(value)?,
+            None => 0,
         };
+        if length > MAX_BODY_BYTES {
+            return Err(HttpParseError::BodyTooLarge);
+        }
 
-        let http_req_method_safe_string = String::from(http_req_method);
-        println!("Http Method: {http_req_method_safe_string}");
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body)?;
+        Ok(body)
+    }
+
+    fn parse_content_length(value: &str) -> Result<usize, HttpParseError> {
+        // repeated Content-Length headers were folded into "n, n", which is fine as long as they all agree
+        let mut lengths = value.split(',').map(|part| part.trim());
+        let first = lengths.next().unwrap_or("");
+        if first.is_empty() || !first.bytes().all(|b| b.is_ascii_digit()) || lengths.any(|other| other != first) {
+            return Err(HttpParseError::InvalidContentLength(String::from(value)));
+        }
 
-        let http_req_path_safe_string = String::from(http_req_path);
-        println!("Http Req Pathname: {http_req_path_safe_string}");
+        // an absurdly long string of digits is still just "too large"
+        first.parse::<usize>().map_err(|_| HttpParseError::BodyTooLarge)
+    }
 
-        let crlf = HttpConstants::get_crlf();
-        let segmented_req_on_line_feed_vec: Vec<&str> = req_buffer.split(crlf).collect();
+    // https://www.rfc-editor.org/rfc/rfc9112#section-7.1
+    fn read_chunked_body<R: BufRead>(reader: &mut R) -> Result<Vec<u8>, HttpParseError> {
+        let mut body: Vec<u8> = Vec::new();
+        // chunk size lines and trailers share one allowance, like the request head
+        let mut line_budget = MAX_REQUEST_HEAD_BYTES;
 
-        // index 0 is the protocol line
-        // index of segmented_req.len() - 1 is http-body
-        // So to iterate through all headers in a \n separated representation of req
-        // we need to iterate through 1 - len() - 2 (len() - 1 is the last non null index in a 0 based array schema)
-        let mut headers: HashMap<String, String> = HashMap::new();
-        let default_null_str = "";
-        let default_null_string = String::new();
+        loop {
+            let size_line = match read_line(reader, &mut line_budget)? {
+                Some(line) => line,
+                None => return Err(HttpParseError::UnexpectedEof),
+            };
 
-        let len = segmented_req_on_line_feed_vec.len() - 2; // last
-        let mut header_index: usize = 1; // first
+            // chunk extensions (";name=value") are allowed but carry nothing we use
+            let size_str = size_line.split(';').next().unwrap_or("").trim();
+            if size_str.is_empty() || !size_str.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(HttpParseError::InvalidChunk(format!("bad chunk size '{}'", size_line)));
+            }
+            let chunk_size = usize::from_str_radix(size_str, 16).map_err(|_| HttpParseError::BodyTooLarge)?;
 
-        while header_index < len {
-            let header_option = segmented_req_on_line_feed_vec.get(header_index);
-            let header_str = *header_option.unwrap();
-            let header_str_vec: Vec<&str> = header_str.split(": ").collect();
-            let header_str_key = *header_str_vec.get(0).unwrap_or(&default_null_str);
-            let header_str_value = *header_str_vec.get(1).unwrap_or(&default_null_str);
+            if chunk_size == 0 {
+                break;
+            }
+            if chunk_size > MAX_BODY_BYTES - body.len() {
+                return Err(HttpParseError::BodyTooLarge);
+            }
 
-            let should_add_header = header_str_key != "" && header_str_value != "";
+            let chunk_start = body.len();
+            body.resize(chunk_start + chunk_size, 0);
+            reader.read_exact(&mut body[chunk_start..])?;
 
-            if should_add_header {
-                let header_string_key = String::from(header_str_key);
-                let header_string_value = String::from(header_str_value);
+            match read_line(reader, &mut line_budget)? {
+                Some(line) if line.is_empty() => {}
+                Some(_) => return Err(HttpParseError::InvalidChunk(String::from("chunk data longer than its size"))),
+                None => return Err(HttpParseError::UnexpectedEof),
+            }
+        }
 
-                headers.insert(header_string_key, header_string_value);
+        // trailer fields are read and discarded up to the terminating blank line
+        loop {
+            match read_line(reader, &mut line_budget)? {
+                Some(line) if line.is_empty() => break,
+                Some(line) => {
+                    parse_header_line(&line)?;
+                }
+                None => return Err(HttpParseError::UnexpectedEof),
             }
-            header_index += 1;
         }
 
-        println!("HttpRequest - Headers: {:#?}", headers);
+        Ok(body)
+    }
 
-        let request_body_str = *segmented_req_on_line_feed_vec
-            .get(len + 1)
-            .unwrap_or(&default_null_str);
-        let serialized_request_body =
-            serde_json::to_string(&request_body_str).unwrap_or(default_null_string);
+    // tchar from rfc9110 5.6.2
+    fn is_token_byte(b: u8) -> bool {
+        b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+    }
 
-        HttpRequest {
-            method: String::from(http_req_method),
-            path: String::from(http_req_path),
-            headers,
-            data: serialized_request_body,
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::io::Cursor;
+
+        fn parse(raw: &str) -> Result<HttpRequest, HttpParseError> {
+            parse_http_request(&mut Cursor::new(raw.as_bytes()))
+        }
+
+        #[test]
+        fn reads_a_content_length_body_and_leaves_the_next_request_unread() {
+            let mut reader = Cursor::new(&b"POST /metric HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET /ping HTTP/1.1\r\n\r\n"[..]);
+
+            let first = parse_http_request(&mut reader).unwrap();
+            assert_eq!(first.get_body(), "hello");
+            let second = parse_http_request(&mut reader).unwrap();
+            assert_eq!(second.path, "/ping");
+            assert!(matches!(parse_http_request(&mut reader), Err(HttpParseError::ConnectionClosed)));
+        }
+
+        #[test]
+        fn reads_a_chunked_body() {
+            let request = parse(
+                "POST /metrics/batch HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                 5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\nx-trailer: ignored\r\n\r\n",
+            )
+            .unwrap();
+            assert_eq!(request.get_body(), "hello, world");
+        }
+
+        #[test]
+        fn read_chunked_body_refuses_bad_chunks() {
+            let read = |raw: &str| read_chunked_body(&mut Cursor::new(raw.as_bytes()));
+
+            assert_eq!(read("3\r\nabc\r\n0\r\n\r\n").unwrap(), b"abc");
+            assert!(matches!(read("zz\r\nabc\r\n0\r\n\r\n"), Err(HttpParseError::InvalidChunk(_))));
+            assert!(matches!(read("2\r\nabc\r\n0\r\n\r\n"), Err(HttpParseError::InvalidChunk(_))));
+            assert!(matches!(read("3\r\nabc\r\n"), Err(HttpParseError::UnexpectedEof)));
+            assert!(matches!(read("fffffffff\r\n"), Err(HttpParseError::BodyTooLarge)));
+        }
+
+        #[test]
+        fn refuses_content_length_with_transfer_encoding() {
+            let result = parse("POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n");
+            assert!(matches!(result, Err(HttpParseError::AmbiguousBodyLength)));
+        }
+
+        #[test]
+        fn accepts_repeated_content_length_only_when_they_agree() {
+            let agreeing = parse("POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 3\r\n\r\nabc").unwrap();
+            assert_eq!(agreeing.get_body(), "abc");
+
+            let disagreeing = parse("POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 4\r\n\r\nabcd");
+            assert!(matches!(disagreeing, Err(HttpParseError::InvalidContentLength(_))));
+        }
+
+        #[test]
+        fn refuses_heads_over_the_limits() {
+            let too_many_headers: String = (0..=MAX_HEADER_COUNT).map(|index| format!("x-header-{}: 1\r\n", index)).collect();
+            let result = parse(&format!("GET / HTTP/1.1\r\n{}\r\n", too_many_headers));
+            assert!(matches!(result, Err(HttpParseError::HeadersTooLarge)));
+
+            let too_long_header = "a".repeat(MAX_REQUEST_HEAD_BYTES);
+            let result = parse(&format!("GET / HTTP/1.1\r\nx-long: {}\r\n\r\n", too_long_header));
+            assert!(matches!(result, Err(HttpParseError::HeadersTooLarge)));
+
+            let exactly_enough: String = (0..MAX_HEADER_COUNT).map(|index| format!("x-header-{}: 1\r\n", index)).collect();
+            assert!(parse(&format!("GET / HTTP/1.1\r\n{}\r\n", exactly_enough)).is_ok());
         }
     }
-}
\ No newline at end of file
+}
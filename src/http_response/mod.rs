@@ -1,68 +1,49 @@
 pub mod http_response {
     use std::collections::HashMap;
-    use crate::http_constants::http_base_kit::http_constants::HttpConstants;
+    use crate::http_constants::http_base_kit::http_constants::{HttpConstants, StatusCode};
 
     pub struct HttpResponse {
-        pub status: usize,
+        pub status: StatusCode,
         pub headers: HashMap<String, String>,
-        pub body: String,
+        // raw bytes, so binary payloads (images) can be sent as well as JSON
+        pub body: Vec<u8>,
     }
 
     impl HttpResponse {
-        pub fn build(&self) -> String {
+        pub fn build(&self) -> Vec<u8> {
             let crlf = HttpConstants::get_crlf();
 
             let mut response_string = String::new();
-            let mut status_line = String::new();
-
-            if self.status == 200 {
-                status_line = HttpConstants::get_success_get_protocol_http_prefix();
-            } else {
-                status_line = HttpConstants::get_error_server_internal_protocol_http_prefix();
-            };
+            let status_line = HttpConstants::get_status_line(self.status);
 
             // scaffolding headers onto response
             let mut header_string = String::new();
 
-            // we map over the hashmap to a vec for formatting ease bc we need to know when to add the crlf
+            // every response carries its body length, without it a kept-alive client
+            // can't tell where this response ends and the next one begins
             let mut header_vector_stash: Vec<(String, String)> = vec![];
             for (key, value) in self.headers.clone() {
-                header_vector_stash.push((String::from(key), String::from(value)));
+                if !key.eq_ignore_ascii_case("Content-Length") {
+                    header_vector_stash.push((key, value));
+                }
             }
+            header_vector_stash.push((String::from("Content-Length"), self.body.len().to_string()));
 
-            let header_vec_len: usize = header_vector_stash.len();
-            let mut header_vec_index_count: usize = 0;
-            while header_vec_index_count < header_vec_len {
-                let header_kv_option = header_vector_stash.get(header_vec_index_count);
-                let header_kv_tuple = header_kv_option.unwrap();
-                let header_kv_string = format!("{}: {}", header_kv_tuple.0, header_kv_tuple.1);
-
-                header_string.push_str(&header_kv_string);
-
-                if header_vec_index_count == header_vec_len - 1 {
-                    header_string.push_str(crlf);
-                    header_string.push_str(crlf);
-                } else {
-                    header_string.push_str("\n");
-                };
-
-                header_vec_index_count += 1;
-            }
-
-            // scaffolding body onto response
-            let mut body_string = String::new();
-            if self.body != String::new() {
-                body_string = self.body.clone();
+            // each header line ends in a crlf, and an empty line (one more crlf) ends the head
+            for (key, value) in header_vector_stash.iter() {
+                header_string.push_str(&format!("{}: {}", key, value));
+                header_string.push_str(crlf);
             }
+            header_string.push_str(crlf);
 
             response_string.push_str(&status_line);
             response_string.push_str(&header_string);
 
-            if body_string != String::new() {
-                response_string.push_str(&body_string);
-            }
+            // scaffolding body onto response
+            let mut response_bytes = response_string.into_bytes();
+            response_bytes.extend_from_slice(&self.body);
 
-            response_string
+            response_bytes
         }
     }
 }
\ No newline at end of file
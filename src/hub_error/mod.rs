@@ -0,0 +1,133 @@
+pub mod hub_error {
+
+    // Every way a request can fail, and the status each one answers with.
+    // Whatever the status, the body keeps the shape clients already parse,
+    // {"errors": [["<kind>", "<message>"], ...]}
+
+    use crate::content_type::content_type_base_kit::ContentHeaders;
+    use crate::cors::cors_base_kit::CorsHeaders;
+    use crate::http_constants::http_base_kit::http_constants::StatusCode;
+    use crate::http_request::http_request_base_kit::HttpParseError;
+    use crate::http_response::http_response::HttpResponse;
+    use std::collections::HashMap;
+
+    #[derive(Debug)]
+    pub enum HubError {
+        // 401, no credentials sent at all
+        Unauthenticated(String),
+        // 403, credentials that were sent but aren't good for this request (wrong, expired)
+        Forbidden(String),
+        // 404
+        NotFound(String),
+        // 405, with the methods the path does take
+        MethodNotAllowed { message: String, allowed: Vec<String> },
+        // 400, a request we couldn't read (not JSON, not HTTP), or a metric that doesn't validate
+        BadRequest(Vec<(String, String)>),
+        // 415
+        UnsupportedMediaType(String),
+        // 422, a read query (/query, /stories/:id/metrics) whose parameters don't describe anything we can answer
+        Validation(Vec<(String, String)>),
+        // 413
+        PayloadTooLarge(Vec<(String, String)>),
+        // 431
+        HeadersTooLarge(String),
+        // 429. Nothing in the hub rate limits yet, this is the error a limiter should return.
+        #[allow(dead_code)]
+        RateLimited { message: String, retry_after_secs: u64 },
+        // 503, every worker busy and the queue full
+        QueueFull(String),
+        // 500s, the hub's fault rather than the client's
+        Config(String),
+        Storage(String),
+    }
+
+    impl HubError {
+        // Metric parsing reports problems as (kind, message) pairs. An oversize payload is a 413,
+        // anything else wrong with a metric (a body we couldn't parse, a field that doesn't validate,
+        // a value that doesn't parse, a metric type we don't know) is a 400.
+        pub fn from_validation_errors(errors: Vec<(String, String)>) -> HubError {
+            if errors.iter().any(|(kind, _)| kind == "PayloadTooLargeError") {
+                HubError::PayloadTooLarge(errors)
+            } else {
+                HubError::BadRequest(errors)
+            }
+        }
+
+        pub fn get_status(&self) -> StatusCode {
+            match self {
+                HubError::Unauthenticated(_) => StatusCode::Unauthorized,
+                HubError::Forbidden(_) => StatusCode::Forbidden,
+                HubError::NotFound(_) => StatusCode::NotFound,
+                HubError::MethodNotAllowed { .. } => StatusCode::MethodNotAllowed,
+                HubError::BadRequest(_) => StatusCode::BadRequest,
+                HubError::UnsupportedMediaType(_) => StatusCode::UnsupportedMediaType,
+                HubError::Validation(_) => StatusCode::UnprocessableEntity,
+                HubError::PayloadTooLarge(_) => StatusCode::PayloadTooLarge,
+                HubError::HeadersTooLarge(_) => StatusCode::RequestHeaderFieldsTooLarge,
+                HubError::RateLimited { .. } => StatusCode::TooManyRequests,
+                HubError::QueueFull(_) => StatusCode::ServiceUnavailable,
+                HubError::Config(_) | HubError::Storage(_) => StatusCode::InternalServerError,
+            }
+        }
+
+        // the (kind, message) pairs that go out in the body
+        pub fn get_errors(&self) -> Vec<(String, String)> {
+            let single = |kind: &str, message: &str| vec![(String::from(kind), String::from(message))];
+
+            match self {
+                HubError::Unauthenticated(message) | HubError::Forbidden(message) => single("CredentialsError", message),
+                HubError::NotFound(message) => single("PathError", message),
+                HubError::MethodNotAllowed { message, .. } => single("MethodError", message),
+                HubError::BadRequest(errors) | HubError::Validation(errors) | HubError::PayloadTooLarge(errors) => {
+                    errors.clone()
+                }
+                HubError::UnsupportedMediaType(message) => single("ValidationError", message),
+                HubError::HeadersTooLarge(message) => single("ParseError", message),
+                HubError::RateLimited { message, .. } => single("RateLimitError", message),
+                HubError::QueueFull(message) => single("QueueFullError", message),
+                HubError::Config(message) => single("ConfigError", message),
+                HubError::Storage(message) => single("StorageError", message),
+            }
+        }
+
+        pub fn into_response(self) -> HttpResponse {
+            let mut headers: HashMap<String, String> = HashMap::new();
+            CorsHeaders::add_cors_to_headers(&mut headers);
+            ContentHeaders::add_content_type_to_headers(&mut headers);
+
+            match &self {
+                HubError::MethodNotAllowed { allowed, .. } => {
+                    headers.insert(String::from("Allow"), allowed.join(", "));
+                }
+                HubError::RateLimited { retry_after_secs, .. } => {
+                    headers.insert(String::from("Retry-After"), retry_after_secs.to_string());
+                }
+                HubError::QueueFull(_) => {
+                    headers.insert(String::from("Retry-After"), String::from("1"));
+                }
+                _ => {}
+            }
+
+            let mut error_hashmap: HashMap<String, Vec<(String, String)>> = HashMap::new();
+            error_hashmap.insert(String::from("errors"), self.get_errors());
+
+            HttpResponse {
+                body: serde_json::to_string(&error_hashmap).unwrap_or_default().into_bytes(),
+                headers,
+                status: self.get_status(),
+            }
+        }
+    }
+
+    // a request too broken to route, the connection handler answers these before any handler runs
+    impl From<&HttpParseError> for HubError {
+        fn from(e: &HttpParseError) -> HubError {
+            let message = format!("[Error]: {}.", e);
+            match e {
+                HttpParseError::BodyTooLarge => HubError::PayloadTooLarge(vec![(String::from("PayloadTooLargeError"), message)]),
+                HttpParseError::HeadersTooLarge => HubError::HeadersTooLarge(message),
+                _ => HubError::BadRequest(vec![(String::from("ParseError"), message)]),
+            }
+        }
+    }
+}
@@ -0,0 +1,411 @@
+pub mod hub_stats {
+
+    // How the hub itself is doing: requests by route and status and how long they took, requests we
+    // couldn't parse or authenticate, bytes read off sockets, connections taken and turned away, and
+    // how long the store takes to write. Served as JSON at GET /admin/stats and alongside the ingested
+    // metrics at GET /metrics/prometheus.
+    //
+    // Every label is one of a fixed set (route patterns, known methods, parse failure kinds, the key
+    // ids in our own key file...), never anything a client typed, so none of these can grow without bound.
+
+    use crate::http_constants::http_base_kit::http_constants::StatusCode;
+    use crate::hub_error::hub_error::HubError;
+    use crate::prometheus::prometheus::{write_family, ExpositionFormat, FamilyKind, Sample};
+    use crate::thread_pool::thread_pool::PoolStats;
+    use std::collections::BTreeMap;
+    use std::io::{self, Read};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    // the route label for requests no route matched, and for ones too broken to route at all
+    pub const UNMATCHED_ROUTE: &str = "unmatched";
+    pub const UNPARSED_ROUTE: &str = "unparsed";
+
+    // upper bounds of the latency buckets, in seconds
+    const LATENCY_BUCKETS_SECS: [f64; 12] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0];
+
+    const KNOWN_METHODS: [&str; 7] = ["GET", "HEAD", "POST", "PUT", "DELETE", "PATCH", "OPTIONS"];
+
+    // any other method a client makes up is counted as "other"
+    pub fn get_method_label(method: &str) -> &'static str {
+        KNOWN_METHODS.iter().find(|known| **known == method).copied().unwrap_or("other")
+    }
+
+    #[derive(Clone, Default)]
+    pub struct LatencyHistogram {
+        // how many observations fell in each bucket (not cumulative), the last one is everything over 5s
+        bucket_counts: [u64; LATENCY_BUCKETS_SECS.len() + 1],
+        count: u64,
+        sum_secs: f64,
+    }
+
+    impl LatencyHistogram {
+        pub fn observe(&mut self, elapsed: Duration) {
+            let secs = elapsed.as_secs_f64();
+            let bucket = LATENCY_BUCKETS_SECS
+                .iter()
+                .position(|upper_bound| secs <= *upper_bound)
+                .unwrap_or(LATENCY_BUCKETS_SECS.len());
+            self.bucket_counts[bucket] += 1;
+            self.count += 1;
+            self.sum_secs += secs;
+        }
+
+        // the _bucket, _sum and _count samples of one histogram, buckets are cumulative in the exposition
+        fn get_samples(&self, labels: &[(String, String)]) -> Vec<Sample> {
+            let mut samples = Vec::with_capacity(LATENCY_BUCKETS_SECS.len() + 3);
+            let mut cumulative: u64 = 0;
+            for (index, bucket_count) in self.bucket_counts.iter().enumerate() {
+                cumulative += bucket_count;
+                let upper_bound = match LATENCY_BUCKETS_SECS.get(index) {
+                    Some(upper_bound) if upper_bound.fract() == 0.0 => format!("{:.1}", upper_bound),
+                    Some(upper_bound) => format!("{}", upper_bound),
+                    None => String::from("+Inf"),
+                };
+                let mut bucket_labels = labels.to_vec();
+                bucket_labels.push((String::from("le"), upper_bound));
+                samples.push(Sample { suffix: "_bucket", labels: bucket_labels, value: cumulative as f64 });
+            }
+            samples.push(Sample { suffix: "_sum", labels: labels.to_vec(), value: self.sum_secs });
+            samples.push(Sample { suffix: "_count", labels: labels.to_vec(), value: self.count as f64 });
+            samples
+        }
+
+        fn to_json(&self) -> serde_json::Value {
+            let buckets: Vec<serde_json::Value> = LATENCY_BUCKETS_SECS
+                .iter()
+                .zip(self.bucket_counts.iter())
+                .map(|(upper_bound, count)| serde_json::json!({ "le_ms": upper_bound * 1000.0, "count": count }))
+                .collect();
+            let average_ms = if self.count == 0 { 0.0 } else { self.sum_secs * 1000.0 / self.count as f64 };
+
+            serde_json::json!({
+                "count": self.count,
+                "average_ms": average_ms,
+                "buckets": buckets,
+                "over_max_bucket": self.bucket_counts[LATENCY_BUCKETS_SECS.len()],
+            })
+        }
+    }
+
+    #[derive(Default)]
+    struct StatsState {
+        // (method, route pattern, status code) -> requests answered
+        requests: BTreeMap<(&'static str, &'static str, usize), u64>,
+        // route pattern -> how long its requests took, from the first byte to the response being ready
+        request_durations: BTreeMap<&'static str, LatencyHistogram>,
+        // HttpParseError reason -> requests we couldn't read
+        parse_failures: BTreeMap<&'static str, u64>,
+        // (scheme, reason) -> requests turned away for their credentials
+        auth_failures: BTreeMap<(&'static str, &'static str), u64>,
+        // (key id, key generation, outcome) -> requests that came with that key
+        api_key_requests: BTreeMap<(String, String, &'static str), u64>,
+        // store operation -> how long its writes took
+        storage_writes: BTreeMap<&'static str, LatencyHistogram>,
+        storage_write_failures: BTreeMap<&'static str, u64>,
+    }
+
+    pub struct HubStats {
+        bytes_read: AtomicU64,
+        connections_accepted: AtomicU64,
+        // turned away with a 503 because the worker queue was full
+        connections_rejected: AtomicU64,
+        state: Mutex<StatsState>,
+    }
+
+    impl Default for HubStats {
+        fn default() -> HubStats {
+            HubStats::new()
+        }
+    }
+
+    impl HubStats {
+        pub fn new() -> HubStats {
+            HubStats {
+                bytes_read: AtomicU64::new(0),
+                connections_accepted: AtomicU64::new(0),
+                connections_rejected: AtomicU64::new(0),
+                state: Mutex::new(StatsState::default()),
+            }
+        }
+
+        pub fn record_connection_accepted(&self) {
+            self.connections_accepted.fetch_add(1, Ordering::SeqCst);
+        }
+
+        pub fn record_connection_rejected(&self) {
+            self.connections_rejected.fetch_add(1, Ordering::SeqCst);
+        }
+
+        pub fn get_connections_accepted(&self) -> u64 {
+            self.connections_accepted.load(Ordering::SeqCst)
+        }
+
+        pub fn get_connections_rejected(&self) -> u64 {
+            self.connections_rejected.load(Ordering::SeqCst)
+        }
+
+        pub fn add_bytes_read(&self, bytes: u64) {
+            self.bytes_read.fetch_add(bytes, Ordering::SeqCst);
+        }
+
+        pub fn record_request(&self, method: &str, route: &'static str, status: StatusCode, elapsed: Duration) {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            *state.requests.entry((get_method_label(method), route, status.get_code())).or_default() += 1;
+            state.request_durations.entry(route).or_default().observe(elapsed);
+        }
+
+        pub fn record_parse_failure(&self, reason: &'static str) {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            *state.parse_failures.entry(reason).or_default() += 1;
+        }
+
+        // scheme is how the caller tried to authenticate ("ulysses_key", "beacon_token"),
+        // only missing (401) and invalid (403) credentials count, any other error is ignored
+        pub fn record_auth_failure(&self, scheme: &'static str, error: &HubError) {
+            let reason = match error {
+                HubError::Unauthenticated(_) => "missing",
+                HubError::Forbidden(_) => "invalid",
+                _ => return,
+            };
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            *state.auth_failures.entry((scheme, reason)).or_default() += 1;
+        }
+
+        // Which generation of which key a request used, so a rotated out key can be dropped once
+        // nothing sends it anymore. outcome is "accepted" once every check passed, otherwise why the
+        // key was refused: "retired" past the generation's cutoff, "expired" or "missing_scope".
+        // Returns how many times that key, generation and outcome have now been counted.
+        pub fn record_api_key_use(&self, key_id: &str, generation: String, outcome: &'static str) -> u64 {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            let count = state.api_key_requests.entry((String::from(key_id), generation, outcome)).or_default();
+            *count += 1;
+            *count
+        }
+
+        pub fn record_storage_write(&self, operation: &'static str, elapsed: Duration, succeeded: bool) {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            state.storage_writes.entry(operation).or_default().observe(elapsed);
+            if !succeeded {
+                *state.storage_write_failures.entry(operation).or_default() += 1;
+            }
+        }
+
+        pub fn write_families(&self, out: &mut String, format: ExpositionFormat, pool: &PoolStats) {
+            let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            let label = |name: &str, value: &str| (String::from(name), String::from(value));
+            let single = |suffix: &'static str, value: f64| [Sample { suffix, labels: vec![], value }];
+
+            let requests: Vec<Sample> = state
+                .requests
+                .iter()
+                .map(|((method, route, status), count)| Sample {
+                    suffix: "_total",
+                    labels: vec![label("method", method), label("route", route), label("status", &status.to_string())],
+                    value: *count as f64,
+                })
+                .collect();
+            write_family(out, format, "couch_gag_hub_requests", "Requests answered, by method, route and status.", FamilyKind::Counter, &requests);
+
+            let durations: Vec<Sample> = state
+                .request_durations
+                .iter()
+                .flat_map(|(route, histogram)| histogram.get_samples(&[label("route", route)]))
+                .collect();
+            write_family(
+                out,
+                format,
+                "couch_gag_hub_request_duration_seconds",
+                "Time from a request's first byte to its response being ready, by route.",
+                FamilyKind::Histogram,
+                &durations,
+            );
+
+            let parse_failures: Vec<Sample> = state
+                .parse_failures
+                .iter()
+                .map(|(reason, count)| Sample { suffix: "_total", labels: vec![label("reason", reason)], value: *count as f64 })
+                .collect();
+            write_family(out, format, "couch_gag_hub_parse_failures", "Requests that could not be parsed as HTTP, by reason.", FamilyKind::Counter, &parse_failures);
+
+            let auth_failures: Vec<Sample> = state
+                .auth_failures
+                .iter()
+                .map(|((scheme, reason), count)| Sample {
+                    suffix: "_total",
+                    labels: vec![label("scheme", scheme), label("reason", reason)],
+                    value: *count as f64,
+                })
+                .collect();
+            write_family(out, format, "couch_gag_hub_auth_failures", "Requests refused for missing or invalid credentials.", FamilyKind::Counter, &auth_failures);
+
+            let api_key_requests: Vec<Sample> = state
+                .api_key_requests
+                .iter()
+                .map(|((key_id, generation, outcome), count)| Sample {
+                    suffix: "_total",
+                    labels: vec![label("key_id", key_id), label("generation", generation), label("outcome", outcome)],
+                    value: *count as f64,
+                })
+                .collect();
+            write_family(
+                out,
+                format,
+                "couch_gag_hub_api_key_requests",
+                "Requests that came with a known api key, by key, key generation and whether the key was accepted.",
+                FamilyKind::Counter,
+                &api_key_requests,
+            );
+
+            write_family(
+                out,
+                format,
+                "couch_gag_hub_read_bytes",
+                "Bytes read off client connections.",
+                FamilyKind::Counter,
+                &single("_total", self.bytes_read.load(Ordering::SeqCst) as f64),
+            );
+            write_family(
+                out,
+                format,
+                "couch_gag_hub_connections_accepted",
+                "Connections handed to a worker.",
+                FamilyKind::Counter,
+                &single("_total", self.get_connections_accepted() as f64),
+            );
+            write_family(
+                out,
+                format,
+                "couch_gag_hub_connections_rejected",
+                "Connections turned away because the worker queue was full.",
+                FamilyKind::Counter,
+                &single("_total", self.get_connections_rejected() as f64),
+            );
+
+            // a worker serves one connection at a time, so busy workers are the open connections
+            write_family(
+                out,
+                format,
+                "couch_gag_hub_active_connections",
+                "Connections being served right now.",
+                FamilyKind::Gauge,
+                &single("", pool.get_busy() as f64),
+            );
+            write_family(
+                out,
+                format,
+                "couch_gag_hub_queued_connections",
+                "Connections waiting for a free worker.",
+                FamilyKind::Gauge,
+                &single("", pool.get_queued() as f64),
+            );
+            write_family(
+                out,
+                format,
+                "couch_gag_hub_queue_capacity",
+                "How many connections may wait for a free worker before new ones are turned away.",
+                FamilyKind::Gauge,
+                &single("", pool.get_queue_depth() as f64),
+            );
+            write_family(out, format, "couch_gag_hub_workers", "Worker threads serving connections.", FamilyKind::Gauge, &single("", pool.get_worker_count() as f64));
+
+            let storage_writes: Vec<Sample> = state
+                .storage_writes
+                .iter()
+                .flat_map(|(operation, histogram)| histogram.get_samples(&[label("operation", operation)]))
+                .collect();
+            write_family(
+                out,
+                format,
+                "couch_gag_hub_storage_write_duration_seconds",
+                "Time taken to write metrics to the store, by operation.",
+                FamilyKind::Histogram,
+                &storage_writes,
+            );
+
+            let storage_write_failures: Vec<Sample> = state
+                .storage_write_failures
+                .iter()
+                .map(|(operation, count)| Sample { suffix: "_total", labels: vec![label("operation", operation)], value: *count as f64 })
+                .collect();
+            write_family(out, format, "couch_gag_hub_storage_write_failures", "Writes to the store that failed, by operation.", FamilyKind::Counter, &storage_write_failures);
+        }
+
+        pub fn to_json(&self, pool: &PoolStats) -> serde_json::Value {
+            let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+
+            let requests: Vec<serde_json::Value> = state
+                .requests
+                .iter()
+                .map(|((method, route, status), count)| {
+                    serde_json::json!({ "method": method, "route": route, "status": status, "count": count })
+                })
+                .collect();
+            let request_durations: BTreeMap<&str, serde_json::Value> =
+                state.request_durations.iter().map(|(route, histogram)| (*route, histogram.to_json())).collect();
+            let auth_failures: Vec<serde_json::Value> = state
+                .auth_failures
+                .iter()
+                .map(|((scheme, reason), count)| serde_json::json!({ "scheme": scheme, "reason": reason, "count": count }))
+                .collect();
+            let api_key_requests: Vec<serde_json::Value> = state
+                .api_key_requests
+                .iter()
+                .map(|((key_id, generation, outcome), count)| {
+                    serde_json::json!({ "key_id": key_id, "generation": generation, "outcome": outcome, "count": count })
+                })
+                .collect();
+            let storage_writes: BTreeMap<&str, serde_json::Value> =
+                state.storage_writes.iter().map(|(operation, histogram)| (*operation, histogram.to_json())).collect();
+
+            serde_json::json!({
+                "requests": requests,
+                "request_durations": request_durations,
+                "parse_failures": state.parse_failures,
+                "auth_failures": auth_failures,
+                "api_key_requests": api_key_requests,
+                "bytes_read": self.bytes_read.load(Ordering::SeqCst),
+                "connections": {
+                    "active": pool.get_busy(),
+                    "accepted": self.get_connections_accepted(),
+                    "rejected_at_capacity": self.get_connections_rejected(),
+                },
+                "queue": {
+                    "queued": pool.get_queued(),
+                    "queue_depth": pool.get_queue_depth(),
+                    "workers": pool.get_worker_count(),
+                },
+                "storage": {
+                    "writes": storage_writes,
+                    "write_failures": state.storage_write_failures,
+                },
+            })
+        }
+    }
+
+    // Counts every byte read through it, wrapped around a socket to see how much a connection sent us.
+    pub struct CountingReader<R> {
+        inner: R,
+        count: u64,
+    }
+
+    impl<R> CountingReader<R> {
+        pub fn new(inner: R) -> CountingReader<R> {
+            CountingReader { inner, count: 0 }
+        }
+
+        // the bytes read since the last call
+        pub fn take_count(&mut self) -> u64 {
+            std::mem::take(&mut self.count)
+        }
+    }
+
+    impl<R: Read> Read for CountingReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let read = self.inner.read(buf)?;
+            self.count += read as u64;
+            Ok(read)
+        }
+    }
+}
@@ -1,6 +1,15 @@
+#![allow(clippy::module_inception)]
+
 use std::io::prelude::*;
+use std::io::BufReader;
 use std::net::TcpListener;
 use std::net::TcpStream;
+use std::path::Path;
+use std::io::ErrorKind;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
 #[macro_use]
 extern crate serde_derive;
@@ -9,22 +18,109 @@ extern crate serde_json;
 
 use std::collections::HashMap;
 
-use crate::metrics::metric::Metric;
+use crate::metrics::metric::{Metric, MetricSource};
 use crate::utils::utils::*;
 use crate::http_request::http_request_base_kit::*;
 use crate::http_response::http_response::HttpResponse;
+use crate::http_constants::http_base_kit::http_constants::StatusCode;
+use crate::hub_error::hub_error::HubError;
+use crate::router::router::{Access, RouteMatch, RouteRequest, Router};
 use crate::cors::cors_base_kit::CorsHeaders;
 use crate::content_type::content_type_base_kit::ContentHeaders;
+use crate::url::url::ReqUrl;
+use crate::api_keys::api_keys::{ApiKey, ApiKeyStore, KeyGeneration, Scope, SHARED_KEY_ID};
+use crate::beacon::beacon_token;
+use crate::config::hub_config::{self, HubConfig};
+use crate::ulysses_key::ulysses_key::HashedKey;
+use crate::storage::metric_store::{AppendLogStore, MetricFilter, MetricStore, RecentMetrics};
+use crate::thread_pool::thread_pool::{PoolStats, ThreadPool};
+use crate::registry::metric_registry::MetricRegistry;
+use crate::quarantine::quarantine::{QuarantineStore, QuarantinedEvent};
+use crate::query::metric_query::MetricQuery;
+use crate::hub_stats::hub_stats::{CountingReader, HubStats, UNMATCHED_ROUTE, UNPARSED_ROUTE};
+use crate::prometheus::prometheus::{self as exposition, CardinalityLimits, ExpositionFormat, MetricAggregator};
 
+pub mod api_keys;
+pub mod beacon;
+pub mod config;
 pub mod cors;
 pub mod content_type;
+pub mod dotenv;
 pub mod http_constants;
 pub mod http_request;
 pub mod http_response;
+pub mod hub_error;
+pub mod hub_stats;
 pub mod metrics;
+pub mod prometheus;
+pub mod quarantine;
+pub mod query;
+pub mod registry;
+pub mod router;
+pub mod storage;
+pub mod thread_pool;
+pub mod ulysses_key;
 pub mod utils;
 pub mod url;
 
+const REQUEST_READ_TIMEOUT_SECS: u64 = 10;
+// how often an idle keep-alive connection looks up from its socket to see if its worker is wanted elsewhere
+const IDLE_POLL_INTERVAL_MILLIS: u64 = 100;
+
+// the smallest valid GIF there is, a single transparent pixel
+const TRANSPARENT_GIF: [u8; 43] = [
+    0x47, 0x49, 0x46, 0x38, 0x39, 0x61, 0x01, 0x00, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0xff, 0xff, 0xff, 0x21, 0xf9, 0x04, 0x01, 0x00, 0x00, 0x00, 0x00, 0x2c, 0x00, 0x00, 0x00, 0x00,
+    0x01, 0x00, 0x01, 0x00, 0x00, 0x02, 0x02, 0x44, 0x01, 0x00, 0x3b,
+];
+
+// what every worker needs to handle a request, built once in main and shared
+pub struct HubContext {
+    // swapped out whole on SIGHUP, see get_config
+    pub config: RwLock<Arc<HubConfig>>,
+    // who may send x-ulysses-key, swapped out along with the config
+    pub api_keys: RwLock<Arc<ApiKeyStore>>,
+    pub store: Arc<dyn MetricStore>,
+    // what the read endpoints answer from, the last query_window_size metrics written to store
+    pub recent_metrics: RecentMetrics,
+    pub registry: MetricRegistry,
+    // events refused for failing validation, see GET /admin/quarantine
+    pub quarantine: QuarantineStore,
+    // running totals of everything recorded, for GET /metrics/prometheus
+    pub aggregator: MetricAggregator,
+    pub router: Router<HubContext>,
+    // how busy the worker pool is, for the readiness check and the hub's own stats
+    pub pool_stats: Arc<PoolStats>,
+    // the hub's own requests, connections and storage writes, see GET /admin/stats
+    pub stats: HubStats,
+    // flipped by SIGTERM / SIGINT, see main
+    pub shutdown_requested: Arc<AtomicBool>,
+    pub started_at: Instant,
+}
+
+impl HubContext {
+    // the config as it is right now, a reload won't change what a request already holds
+    pub fn get_config(&self) -> Arc<HubConfig> {
+        Arc::clone(&self.config.read().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    pub fn get_api_keys(&self) -> Arc<ApiKeyStore> {
+        Arc::clone(&self.api_keys.read().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+// where the config is read from, kept for reloads
+pub struct ConfigPaths {
+    pub toml_path: String,
+    pub dotenv_path: String,
+}
+
+// how long a kept-alive connection may sit idle, and how many requests it may carry
+pub struct KeepAliveLimits {
+    pub idle_timeout: Duration,
+    pub max_requests: usize,
+}
+
 /**
 * Main Entry Point of the Microservice;
 */
@@ -49,6 +145,30 @@ fn main() {
     // if, for whatever reason, the result of the ::bind operation is an Error
     // we must cease all operations and fail process
     // most* other error cases must be handled in non panic!-ing ways as to not crash the server
+    // Settings come from config/hub.toml (or HUB_CONFIG_PATH), .env and the environment, see HubConfig.
+    // Like everything else up here, a config we can't make sense of stops the server from starting.
+    let config_paths = ConfigPaths {
+        toml_path: get_env_var_or("HUB_CONFIG_PATH", "config/hub.toml"),
+        dotenv_path: String::from(".env"),
+    };
+
+    // `couch-gag-metrics-hub hash-key` prints the hashed form of a key, for ULYSSES_HASHED_KEY
+    if std::env::args().nth(1).as_deref() == Some("hash-key") {
+        print_hashed_key(&config_paths);
+        return;
+    }
+
+    let config = match HubConfig::load(Path::new(&config_paths.toml_path), Path::new(&config_paths.dotenv_path)) {
+        Ok(config) => config,
+        Err(errors) => {
+            println!("Error thrown while loading the config;");
+            for error in errors.iter() {
+                println!("[error]: {}", error);
+            }
+            panic!("[error]: invalid config, {} problem(s) found", errors.len());
+        }
+    };
+
     let listener_result: Result<TcpListener, _> = TcpListener::bind("0.0.0.0:7878");
     let listener: TcpListener = match listener_result {
         Ok(listener) => listener,
@@ -60,6 +180,77 @@ fn main() {
         }
     };
 
+    // Every accepted metric is appended to this log so it survives a restart.
+    // Like the bind above, if we can't open it there is no point in serving requests.
+    let metrics_log_path = config.metrics_log_path.clone();
+    let store: Arc<dyn MetricStore> = match AppendLogStore::open(Path::new(&metrics_log_path)) {
+        Ok(store) => Arc::new(store),
+        Err(e) => {
+            println!("Error thrown while opening the metric log at {};", &metrics_log_path);
+            let error_string = format!("[error]: {}", e);
+            println!("{}", &error_string);
+            panic!("{}", error_string);
+        }
+    };
+
+    // The Prometheus counters start from what's already in the log, so a restart doesn't reset them.
+    // The caps keep a flood of distinct targets or tag values from turning into a flood of series.
+    // The read endpoints get the tail of the log, so they don't come back empty after a restart either.
+    let aggregator = MetricAggregator::new(CardinalityLimits {
+        max_targets_per_type: config.prometheus_max_targets,
+        max_tag_keys: config.prometheus_max_tag_keys,
+        max_values_per_tag: config.prometheus_max_tag_values,
+        max_series: config.prometheus_max_series,
+    });
+    let recent_metrics = RecentMetrics::new(config.query_window_size);
+    let read_back = store.read_each(&mut |metric| {
+        aggregator.record(&metric);
+        recent_metrics.push(metric);
+    });
+    match read_back {
+        Ok(count) => {
+            println!("Metric log {} holds {} metrics", &metrics_log_path, count);
+            println!("Queries see the last {} of them", count.min(recent_metrics.get_capacity()));
+        }
+        Err(e) => println!("[warn]: could not read back metric log {} ({})", &metrics_log_path, e),
+    }
+
+    // The metric types we accept, the six built ins plus whatever the registry file adds.
+    // A registry file that doesn't parse is fatal too, we'd be rejecting metrics we were asked to take.
+    let strict_metric_types = config.strict_metric_types;
+    let registry = match MetricRegistry::load(Path::new(&config.metric_registry_path), strict_metric_types) {
+        Ok(registry) => registry,
+        Err(e) => {
+            println!("Error thrown while loading the metric registry;");
+            let error_string = format!("[error]: {}", e);
+            println!("{}", &error_string);
+            panic!("{}", error_string);
+        }
+    };
+    println!(
+        "Metric registry knows {} metric types ({} mode)",
+        registry.get_definitions().len(),
+        if strict_metric_types { "strict" } else { "lenient" }
+    );
+
+    // The keys our services authenticate with. Refusing to start beats locking a service out.
+    let api_keys = match ApiKeyStore::load(&config, &registry) {
+        Ok(api_keys) => api_keys,
+        Err(errors) => {
+            println!("Error thrown while loading the api keys;");
+            for error in errors.iter() {
+                println!("[error]: {}", error);
+            }
+            panic!("[error]: invalid key file, {} problem(s) found", errors.len());
+        }
+    };
+    let key_ids: Vec<&str> = api_keys.get_keys().iter().map(|key| key.id.as_str()).collect();
+    println!("Accepting {} api keys ({})", key_ids.len(), key_ids.join(", "));
+    log_previous_key_generations(&api_keys);
+    if !api_keys.is_empty() && config.ulysses_key_pepper.is_none() {
+        println!("[warn]: ULYSSES_KEY_PEPPER is not set, api keys are hashed with their salt alone");
+    }
+
     // The incoming method on TcpListener returns an iterator that gives us a sequence of streams
     // (more specifically, streams of type TcpStream).
     // A single stream represents an open connection between the client and the server.
@@ -69,91 +260,1082 @@ fn main() {
     // and then allow us to write our response to the stream (Response).
     // Overall, this for loop will process each connection in turn and produce a series of streams for us to handle.
 
-    for stream in listener.incoming() {
-        let stream: TcpStream = stream.unwrap();
+    // Rather than handling each stream on this thread, we hand it to a pool of workers,
+    // so a single slow client can't stall every other emitter.
+    // https://doc.rust-lang.org/book/ch16-00-concurrency.html
+    let worker_count = config.worker_count;
+    let queue_depth = config.queue_depth;
+    println!("Starting {} workers with a queue depth of {}", worker_count, queue_depth);
+    let pool_stats = Arc::new(PoolStats::new(worker_count, queue_depth));
+
+    let keep_alive_limits = KeepAliveLimits {
+        idle_timeout: Duration::from_secs(config.keep_alive_idle_secs),
+        max_requests: config.keep_alive_max_requests,
+    };
+
+    // SIGTERM (docker-compose down) and SIGINT (ctrl-c) only flip this flag,
+    // the accept loop below notices it and starts winding the server down
+    let shutdown_requested = Arc::new(AtomicBool::new(false));
+    for signal in signal_hook::consts::TERM_SIGNALS {
+        if let Err(e) = signal_hook::flag::register(*signal, Arc::clone(&shutdown_requested)) {
+            println!("[warn]: could not install handler for signal {} ({})", signal, e);
+        }
+    }
+
+    // SIGHUP (docker kill -s HUP) re-reads the config, the accept loop picks it up the same way
+    let reload_requested = Arc::new(AtomicBool::new(false));
+    if let Err(e) = signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&reload_requested)) {
+        println!("[warn]: could not install handler for SIGHUP, config reloads are off ({})", e);
+    }
+
+    let quarantine_capacity = config.quarantine_capacity;
+    let grace_secs = config.shutdown_grace_secs;
+
+    let hub = Arc::new(HubContext {
+        config: RwLock::new(Arc::new(config)),
+        api_keys: RwLock::new(Arc::new(api_keys)),
+        store: Arc::clone(&store),
+        recent_metrics,
+        registry,
+        quarantine: QuarantineStore::new(quarantine_capacity),
+        aggregator,
+        router: build_router(),
+        pool_stats: Arc::clone(&pool_stats),
+        stats: HubStats::new(),
+        shutdown_requested: Arc::clone(&shutdown_requested),
+        started_at: Instant::now(),
+    });
+    for route in hub.router.get_routes() {
+        println!("Serving {} {}", route.method, route.pattern);
+    }
+
+    let worker_hub = Arc::clone(&hub);
+    let pool = ThreadPool::new(pool_stats, move |stream: TcpStream| {
+        // handle_connection destroys the stream & therefore should take ownership
+        handle_connection(stream, &worker_hub, &keep_alive_limits);
+    });
+
+    // a blocking accept() would never give us the chance to look at the flag,
+    // so we poll the listener instead
+    if let Err(e) = listener.set_nonblocking(true) {
+        panic!("[error]: could not make listener non-blocking ({})", e);
+    }
+
+    while !shutdown_requested.load(Ordering::SeqCst) {
+        if reload_requested.swap(false, Ordering::SeqCst) {
+            reload_config(&hub, &config_paths);
+        }
+
+        let stream: TcpStream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(10));
+                continue;
+            }
+            Err(e) => {
+                println!("[error]: failed to accept connection ({})", e);
+                continue;
+            }
+        };
+
+        // the accepted stream inherits non-blocking mode from the listener on some platforms
+        if let Err(e) = stream.set_nonblocking(false) {
+            println!("[error]: dropping connection that could not be made blocking ({})", e);
+            continue;
+        }
+
+        // backpressure: every worker is busy and the queue is full, so turn the client away now
+        // instead of letting it wait on a connection nobody will get to
+        match pool.try_dispatch(stream) {
+            Ok(()) => hub.stats.record_connection_accepted(),
+            Err(stream) => {
+                hub.stats.record_connection_rejected();
+                reject_connection_queue_full(stream);
+            }
+        }
+    }
+
+    // GRACEFUL SHUTDOWN
+    // The listener is dropped first so new clients get refused straight away,
+    // then in-flight connections get until the deadline to finish, then whatever
+    // the store still has buffered is pushed to disk.
+
+    let shutdown_started = Instant::now();
+    drop(listener);
+    println!("Shutdown requested, no longer accepting connections;");
+
+    let abandoned_workers = pool.shutdown(Duration::from_secs(grace_secs));
+
+    let flush_result = match store.flush() {
+        Ok(()) => String::from("flushed"),
+        Err(e) => format!("flush failed ({})", e),
+    };
+
+    println!(
+        "[shutdown]: {} connections dispatched, {} rejected at capacity, {} workers still busy after {}s deadline, metric log {}, stopped in {}ms",
+        hub.stats.get_connections_accepted(),
+        hub.stats.get_connections_rejected(),
+        abandoned_workers,
+        grace_secs,
+        flush_result,
+        shutdown_started.elapsed().as_millis()
+    );
+}
+
+// Reads a key off stdin and prints its salted hash, using the pepper the hub itself would.
+// echo -n "$KEY" | couch-gag-metrics-hub hash-key
+fn print_hashed_key(paths: &ConfigPaths) {
+    let pepper = match hub_config::read_secret(Path::new(&paths.toml_path), Path::new(&paths.dotenv_path), "ulysses_key_pepper") {
+        Ok(pepper) => pepper,
+        Err(errors) => {
+            for error in errors.iter() {
+                println!("[error]: {}", error);
+            }
+            panic!("[error]: could not read ULYSSES_KEY_PEPPER from the config");
+        }
+    };
+    if pepper.is_none() {
+        eprintln!("[warn]: ULYSSES_KEY_PEPPER is not set, hashing with the salt alone");
+    }
+
+    let mut key = String::new();
+    if let Err(e) = std::io::stdin().read_line(&mut key) {
+        panic!("[error]: could not read the key from stdin ({})", e);
+    }
+    let key = key.trim_end_matches(&['\r', '\n'][..]);
+    if key.is_empty() {
+        panic!("[error]: no key given, pipe the key into hash-key on stdin");
+    }
+
+    match HashedKey::hash(key, pepper.as_deref().unwrap_or("")) {
+        Ok(hashed_key) => println!("{}", hashed_key),
+        Err(e) => panic!("[error]: could not read a random salt ({})", e),
+    }
+}
 
-        handle_connection(stream); // handle_connection destroys the stream & therefore should take ownership
+// every rotated out key generation and until when it's accepted, so a forgotten cutoff shows up in the log
+fn log_previous_key_generations(api_keys: &ApiKeyStore) {
+    let now = get_unix_time_secs();
+    for key in api_keys.get_keys() {
+        for generation in key.get_previous_generations() {
+            if let KeyGeneration::Previous { valid_until, .. } = generation {
+                println!(
+                    "Api key '{}' {} is {} {}",
+                    key.id,
+                    generation.get_label(),
+                    if generation.is_retired(now) { "retired since" } else { "accepted until" },
+                    valid_until
+                );
+            }
+        }
     }
 }
 
-fn handle_connection(mut stream: TcpStream) {
+// Swaps in a freshly read config and key file. Either one not loading is reported and the old
+// pair kept, so a typo in .env can't take a running hub down.
+fn reload_config(hub: &HubContext, paths: &ConfigPaths) {
+    let reloaded = HubConfig::load(Path::new(&paths.toml_path), Path::new(&paths.dotenv_path)).and_then(|reloaded| {
+        let api_keys = ApiKeyStore::load(&reloaded, &hub.registry)?;
+        Ok((reloaded, api_keys))
+    });
+    let (reloaded, api_keys) = match reloaded {
+        Ok(reloaded) => reloaded,
+        Err(errors) => {
+            for error in errors.iter() {
+                println!("[warn]: config reload: {}", error);
+            }
+            println!("[warn]: config reload failed, still running on the previous config");
+            return;
+        }
+    };
 
-    // WORKING WITH THE REQUEST
-     
-    let mut buffer = [0; 1024];
-    stream.read(&mut buffer).unwrap();
+    let restart_only_changes = hub.get_config().get_restart_only_changes(&reloaded);
+    if !restart_only_changes.is_empty() {
+        println!(
+            "[warn]: config reload: {} changed, which only takes effect after a restart",
+            restart_only_changes.join(", ")
+        );
+    }
 
-    let http_request_struct_inst = parse_http_request_from_buffer(&String::from_utf8_lossy(&buffer[..]));
-    let req_url_struct_inst = get_url_from_req(&String::from_utf8_lossy(&buffer[..]).to_string());
+    let key_count = api_keys.get_keys().len();
+    *hub.config.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(reloaded);
+    *hub.api_keys.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(api_keys);
+    println!(
+        "Config reloaded from {}, {} and the environment, accepting {} api keys",
+        paths.toml_path, paths.dotenv_path, key_count
+    );
+    log_previous_key_generations(&hub.get_api_keys());
+}
 
-    // store the request in a Clone-on-write<_, String> (smart pointer type)
-    // let request = String::from_utf8_lossy(&buffer[..]);
-    // println!("Request: {} \n", request);
+fn reject_connection_queue_full(mut stream: TcpStream) {
+    // this runs on the accept loop, so never let a slow client hold it up
+    let _ = stream.set_write_timeout(Some(Duration::from_millis(250)));
 
-    let mut errors: Vec<(String, String)> = Vec::new();
+    let http_response = HubError::QueueFull(String::from("[Error]: Server is at capacity, retry shortly.")).into_response();
 
-    let method = http_request_struct_inst.get_http_method();
-    // get_http_method(&request.clone().to_string(), &mut method);
+    if let Err(e) = stream.write_all(&http_response.build()) {
+        println!("[warn]: failed to send 503 to client ({})", e);
+    }
+}
+
+// Serves every request a client sends over one connection.
+// Connections are kept open between requests (keep-alive) until the client asks to close,
+// goes idle, reaches the per-connection request cap, or the server starts shutting down.
+// Pipelined requests just queue up in the reader's buffer and are answered in order.
+fn handle_connection(stream: TcpStream, hub: &HubContext, limits: &KeepAliveLimits) {
+    // &TcpStream implements both Read and Write, so the reader and the writer can share the one socket
+    let mut reader = BufReader::new(CountingReader::new(&stream));
+    let mut writer = &stream;
+    let mut requests_served: usize = 0;
+
+    loop {
+        // WAITING FOR THE NEXT REQUEST
+
+        // the first request gets the full read timeout, after that the connection is idle
+        // and only gets the (shorter) keep-alive window to start sending again
+        let has_request = if requests_served == 0 {
+            wait_for_first_request(&stream, &mut reader)
+        } else {
+            wait_for_next_request(&stream, &mut reader, hub, limits.idle_timeout)
+        };
+        if !has_request {
+            return;
+        }
+
+        // a request has started, give the client the full timeout to finish sending it
+        if let Err(e) = stream.set_read_timeout(Some(Duration::from_secs(REQUEST_READ_TIMEOUT_SECS))) {
+            println!("[warn]: could not set read timeout ({})", e);
+            return;
+        }
+
+        // WORKING WITH THE REQUEST
+
+        let request_started = Instant::now();
+        let parse_result = parse_http_request(&mut reader);
+        hub.stats.add_bytes_read(reader.get_mut().take_count());
+        if let Err(e) = &parse_result {
+            if !matches!(e, HttpParseError::ConnectionClosed) {
+                hub.stats.record_parse_failure(e.get_reason());
+            }
+        }
+
+        let http_request_struct_inst = match parse_result {
+            Ok(request) => request,
+            // nothing to answer, the client is already gone
+            Err(HttpParseError::ConnectionClosed) | Err(HttpParseError::UnexpectedEof) => return,
+            Err(HttpParseError::Io(e)) => {
+                println!("[warn]: dropping connection after read failure ({})", e);
+                return;
+            }
+            Err(e) => {
+                // we can't tell where the next request would start, so the connection is closed
+                println!("[warn]: rejecting malformed request ({})", e);
+                let mut http_response = HubError::from(&e).into_response();
+                http_response.headers.insert(String::from("Connection"), String::from("close"));
+                hub.stats.record_request("", UNPARSED_ROUTE, http_response.status, request_started.elapsed());
+                let _ = writer.write_all(&http_response.build());
+                return;
+            }
+        };
+        requests_served += 1;
+
+        let mut http_request_struct_inst = http_request_struct_inst;
+        http_request_struct_inst.remote_addr = stream.peer_addr().ok().map(|addr| addr.ip().to_string());
+
+        let mut http_response = handle_request(&http_request_struct_inst, hub);
+
+        let route = hub.router.get_pattern(&get_url_from_req(&http_request_struct_inst).path).unwrap_or(UNMATCHED_ROUTE);
+        hub.stats.record_request(
+            &http_request_struct_inst.get_http_method(),
+            route,
+            http_response.status,
+            request_started.elapsed(),
+        );
+
+        // WORKING OUT WHETHER TO KEEP THE CONNECTION
+
+        let keep_alive = client_wants_keep_alive(&http_request_struct_inst)
+            && requests_served < limits.max_requests
+            && !hub.shutdown_requested.load(Ordering::SeqCst);
 
-    let path = http_request_struct_inst.get_path();
+        if keep_alive {
+            http_response.headers.insert(String::from("Connection"), String::from("keep-alive"));
+            http_response.headers.insert(
+                String::from("Keep-Alive"),
+                format!(
+                    "timeout={}, max={}",
+                    limits.idle_timeout.as_secs(),
+                    limits.max_requests - requests_served
+                ),
+            );
+        } else {
+            http_response.headers.insert(String::from("Connection"), String::from("close"));
+        }
+
+        let response = http_response.build();
+
+        if let Err(e) = writer.write_all(&response).and_then(|_| writer.flush()) {
+            println!("[warn]: failed to send response ({})", e);
+            return;
+        }
+
+        if !keep_alive {
+            return;
+        }
+    }
+}
+
+// true once the first byte of a request is buffered, false when the client hung up,
+// broke the socket or sent nothing before the read timeout
+fn wait_for_first_request(stream: &TcpStream, reader: &mut BufReader<CountingReader<&TcpStream>>) -> bool {
+    if let Err(e) = stream.set_read_timeout(Some(Duration::from_secs(REQUEST_READ_TIMEOUT_SECS))) {
+        println!("[warn]: could not set read timeout ({})", e);
+        return false;
+    }
+
+    // fill_buf blocks until the first byte arrives, or returns empty once the client hangs up
+    matches!(reader.fill_buf(), Ok(buffered) if !buffered.is_empty())
+}
+
+// Like wait_for_first_request, but for a kept-alive connection. An idle connection holds a worker
+// the whole time it waits, so it waits in short slices and gives the worker up as soon as other
+// connections are queued for one, the client can always reconnect.
+fn wait_for_next_request(
+    stream: &TcpStream,
+    reader: &mut BufReader<CountingReader<&TcpStream>>,
+    hub: &HubContext,
+    idle_timeout: Duration,
+) -> bool {
+    let poll_interval = Duration::from_millis(IDLE_POLL_INTERVAL_MILLIS).min(idle_timeout);
+    if let Err(e) = stream.set_read_timeout(Some(poll_interval)) {
+        println!("[warn]: could not set read timeout ({})", e);
+        return false;
+    }
 
-    if !is_valid_path(&path) {
-        let error = String::from("[Error] Attempt to access an inaccessible path.");
-        errors.push((String::from("PathError"), error));
+    let idle_since = Instant::now();
+    loop {
+        match reader.fill_buf() {
+            Ok([]) => return false,
+            Ok(_) => return true,
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+            Err(_) => return false,
+        }
+
+        if idle_since.elapsed() >= idle_timeout
+            || hub.pool_stats.get_queued() > 0
+            || hub.shutdown_requested.load(Ordering::SeqCst)
+        {
+            return false;
+        }
+    }
+}
+
+// HTTP/1.1 connections are persistent unless the client says otherwise,
+// HTTP/1.0 connections close unless the client opts in (rfc9112 9.3)
+fn client_wants_keep_alive(request: &HttpRequest) -> bool {
+    let connection = request.get_header_by_key(String::from("Connection")).to_ascii_lowercase();
+    let has_option = |option: &str| connection.split(',').any(|token| token.trim() == option);
+
+    if request.version == "HTTP/1.0" {
+        has_option("keep-alive")
+    } else {
+        !has_option("close")
+    }
+}
+
+// POST bodies must declare themselves as JSON before we try to read them as a metric
+fn get_metric_off_json_request(request: &HttpRequest, registry: &MetricRegistry) -> Result<Metric, HubError> {
+    let content_type = request.get_header_by_key(String::from("Content-Type"));
+    let media_type = content_type.split(';').next().unwrap_or("").trim();
+
+    if !media_type.eq_ignore_ascii_case("application/json") {
+        return Err(HubError::UnsupportedMediaType(format!(
+            "content-type: must be application/json, got '{}'",
+            content_type
+        )));
+    }
+
+    Metric::get_metric_off_json_body(&request.get_body(), registry).map_err(HubError::from_validation_errors)
+}
+
+// POST /metrics/batch
+// Every item is validated on its own and reported back by index. The items that pass
+// are written to the store as one unit, so a batch is never half recorded.
+fn handle_metric_batch(hub: &HubContext, route: &RouteRequest) -> Result<HttpResponse, HubError> {
+    let request = route.request;
+    let content_type = request.get_header_by_key(String::from("Content-Type"));
+    let media_type = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    let is_ndjson = match media_type.as_str() {
+        "application/json" => false,
+        "application/x-ndjson" | "application/ndjson" => true,
+        _ => {
+            return Err(HubError::UnsupportedMediaType(format!(
+                "content-type: must be application/json or application/x-ndjson, got '{}'",
+                content_type
+            )));
+        }
+    };
+
+    let items = Metric::get_metrics_off_batch_body(&request.get_body(), is_ndjson, &hub.registry)
+        .map_err(|batch_errors| quarantine_rejected(hub, request, HubError::from_validation_errors(batch_errors)))?;
+
+    let received_at = get_unix_time_millis();
+    let default_source = get_default_source(route.api_key);
+    let mut accepted: Vec<Metric> = Vec::new();
+    let mut results: Vec<serde_json::Value> = Vec::with_capacity(items.len());
+    // every rejected item's errors, prefixed with where in the batch it sat
+    let mut rejected_errors: Vec<(String, String)> = Vec::new();
+    for (index, item) in items.into_iter().enumerate() {
+        match item {
+            Ok(mut metric) => match check_metric_type_allowed(route.api_key, &metric) {
+                Ok(()) => {
+                    metric.enrich(request, received_at, default_source, route.get_key_id());
+                    accepted.push(metric);
+                    results.push(serde_json::json!({ "index": index, "status": "accepted" }));
+                }
+                // the item is fine, the key just can't send it, so it isn't quarantined
+                Err(e) => {
+                    results.push(serde_json::json!({ "index": index, "status": "rejected", "errors": e.get_errors() }));
+                }
+            },
+            Err(item_errors) => {
+                rejected_errors.extend(
+                    item_errors
+                        .iter()
+                        .map(|(kind, message)| (kind.clone(), format!("items[{}].{}", index, message))),
+                );
+                results.push(serde_json::json!({ "index": index, "status": "rejected", "errors": item_errors }));
+            }
+        }
     }
 
+    // a partly rejected batch still answers 200, the per item results say what happened
+    if !rejected_errors.is_empty() {
+        quarantine_rejected(hub, request, HubError::from_validation_errors(rejected_errors));
+    }
+
+    if !accepted.is_empty() {
+        store_metrics(hub, &accepted).map_err(|e| {
+            HubError::Storage(format!("[Error]: Failed to record batch, no metrics were stored ({}).", e))
+        })?;
+    }
+
+    let body = serde_json::json!({
+        "accepted": accepted.len(),
+        "rejected": results.len() - accepted.len(),
+        "results": results,
+    });
+
+    Ok(HttpResponse {
+        body: body.to_string().into_bytes(),
+        headers: get_json_headers(),
+        status: StatusCode::Ok
+    })
+}
+
+fn get_beacon_secret(config: &HubConfig) -> Result<String, HubError> {
+    config
+        .beacon_secret
+        .clone()
+        .ok_or_else(|| HubError::Config(String::from("[Error]: Beacon ingestion is not configured.")))
+}
+
+// GET /beacon/token
+// Hands an authenticated service (couch-gag-website's server) a token to render into pages for
+// sendBeacon or tracking pixels. An optional ?ttl=<secs> asks for a longer lived token, which
+// newsletters need since they're opened days after they're sent; it's capped by beacon_token_max_ttl_secs,
+// and by when the key asking for it expires, a token can't outlive the key it speaks for.
+fn handle_beacon_token(hub: &HubContext, route: &RouteRequest) -> Result<HttpResponse, HubError> {
+    let config = hub.get_config();
+    let secret = get_beacon_secret(&config)?;
+    let now = get_unix_time_secs();
+
+    let requested_ttl_secs = route.get_query_param("ttl").and_then(|value| value.parse::<u64>().ok());
+    let mut ttl_secs = requested_ttl_secs.unwrap_or(config.beacon_token_ttl_secs).min(config.beacon_token_max_ttl_secs);
+    if let Some(key_expires_at) = route.api_key.and_then(|api_key| api_key.expires_at) {
+        if key_expires_at <= now {
+            return Err(HubError::Forbidden(String::from("[Error]: Ulysses key has expired, no token was minted.")));
+        }
+        ttl_secs = ttl_secs.min(key_expires_at - now);
+    }
+
+    let expires_at = now + ttl_secs;
+    let body = serde_json::json!({
+        "token": beacon_token::mint_token(&secret, expires_at, route.get_key_id().as_deref()),
+        "expires_at": expires_at,
+    });
+
+    Ok(HttpResponse {
+        body: body.to_string().into_bytes(),
+        headers: get_json_headers(),
+        status: StatusCode::Ok
+    })
+}
+
+// Ok with the key that minted the beacon token when the token is ours and still live, and that key is
+// still one we accept for ingest (it may have been removed, expired or lost the scope since).
+// Tokens from before key ids were signed in are the shared key's. A missing token is a 401,
+// one that's there but forged, expired or minted by a key we no longer accept is a 403.
+fn check_beacon_token(hub: &HubContext, secret: &str, token: Option<&str>) -> Result<ApiKey, HubError> {
+    let now = get_unix_time_secs();
+    let checked = match token {
+        None => Err(HubError::Unauthenticated(String::from("[Error]: Beacon token is missing."))),
+        Some(token) => beacon_token::verify_token(secret, token, now)
+            .map_err(|reason| HubError::Forbidden(format!("[Error]: Invalid beacon token, {}.", reason)))
+            .and_then(|verified| {
+                let key_id = verified.key_id.unwrap_or_else(|| String::from(SHARED_KEY_ID));
+                match hub.get_api_keys().get(&key_id) {
+                    None => Err(HubError::Forbidden(format!(
+                        "[Error]: Invalid beacon token, ulysses key '{}' that minted it is no longer accepted.",
+                        key_id
+                    ))),
+                    Some(api_key) if api_key.is_expired(now) => Err(HubError::Forbidden(format!(
+                        "[Error]: Invalid beacon token, ulysses key '{}' that minted it has expired.",
+                        key_id
+                    ))),
+                    Some(api_key) if !api_key.has_scope(Scope::Ingest) => Err(HubError::Forbidden(format!(
+                        "[Error]: Invalid beacon token, ulysses key '{}' that minted it does not have the ingest scope.",
+                        key_id
+                    ))),
+                    Some(api_key) => Ok(api_key.clone()),
+                }
+            }),
+    };
+
+    if let Err(e) = &checked {
+        hub.stats.record_auth_failure("beacon_token", e);
+    }
+    checked
+}
+
+// POST /beacon
+// navigator.sendBeacon payloads, either a text/plain JSON metric object with a "token" field,
+// or an application/x-www-form-urlencoded form with the same fields as the query param route plus "token".
+fn handle_beacon(hub: &HubContext, route: &RouteRequest) -> Result<HttpResponse, HubError> {
+    let request = route.request;
+    let secret = get_beacon_secret(&hub.get_config())?;
+
+    let content_type = request.get_header_by_key(String::from("Content-Type"));
+    let media_type = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+
+    let (token, metric_result) = match media_type.as_str() {
+        "text/plain" => match serde_json::from_str::<serde_json::Value>(&request.get_body()) {
+            Ok(serde_json::Value::Object(mut fields)) => {
+                let token = match fields.remove("token") {
+                    Some(serde_json::Value::String(token)) => Some(token),
+                    _ => None,
+                };
+                (token, Metric::get_metric_off_json_value(serde_json::Value::Object(fields), &hub.registry))
+            }
+            Ok(_) => (None, Err(vec![(String::from("ValidationError"), String::from("body: must be a JSON object"))])),
+            Err(e) => (None, Err(vec![(String::from("ParseError"), format!("body: is not valid JSON ({})", e))])),
+        },
+        "application/x-www-form-urlencoded" => {
+            let fields = parse_form_urlencoded(&request.get_body());
+            let token = fields.iter().find(|(key, _)| key == "token").map(|(_, value)| value.clone());
+            let form_url = ReqUrl {
+                path: request.get_path(),
+                query_parameters: fields.into_iter().filter(|(key, _)| key != "token").collect(),
+            };
+            (token, Metric::get_metric_off_query_params(&form_url, &hub.registry))
+        }
+        _ => {
+            return Err(HubError::UnsupportedMediaType(format!(
+                "content-type: must be text/plain or application/x-www-form-urlencoded, got '{}'",
+                content_type
+            )));
+        }
+    };
+
+    // the token is checked before the payload, an unauthenticated client learns nothing about our schema
+    let api_key = check_beacon_token(hub, &secret, token.as_deref())?;
+
+    let mut metric = metric_result
+        .map_err(|validation_errors| quarantine_rejected(hub, request, HubError::from_validation_errors(validation_errors)))?;
+    // only browsers send beacons
+    check_metric_type_allowed(Some(&api_key), &metric)?;
+    metric.enrich(request, get_unix_time_millis(), MetricSource::Website, Some(api_key.id));
+
+    record_metric(hub, metric)
+}
+
+// GET /pixel.gif?metric=...&target=...&token=...
+// For newsletters and no-JS pages, an <img> pointing here records a metric from its query params.
+// The GIF is returned whatever happens; an error response would only render as a broken image,
+// so failures are logged instead of reported.
+fn handle_pixel(hub: &HubContext, route: &RouteRequest) -> Result<HttpResponse, HubError> {
+    if let Err(e) = record_pixel_metric(hub, route) {
+        let messages: Vec<String> = e.get_errors().into_iter().map(|(_, message)| message).collect();
+        println!("[warn]: pixel hit not recorded ({})", messages.join(", "));
+    }
+
+    let mut headers: HashMap<String, String> = HashMap::new();
+    CorsHeaders::add_cors_to_headers(&mut headers);
+    ContentHeaders::add_content_type_to_headers_as(&mut headers, "image/gif");
+    // every open has to reach us, a cached pixel is a lost metric
+    headers.insert(String::from("Cache-Control"), String::from("no-store, no-cache, must-revalidate, private"));
+    headers.insert(String::from("Pragma"), String::from("no-cache"));
+
+    Ok(HttpResponse {
+        body: TRANSPARENT_GIF.to_vec(),
+        headers,
+        status: StatusCode::Ok
+    })
+}
+
+fn record_pixel_metric(hub: &HubContext, route: &RouteRequest) -> Result<(), HubError> {
+    let request = route.request;
+    let req_url = &route.url;
+
+    let secret = get_beacon_secret(&hub.get_config())?;
+    let api_key = check_beacon_token(hub, &secret, route.get_query_param("token"))?;
+
+    let metric_url = ReqUrl {
+        path: req_url.path.clone(),
+        query_parameters: req_url.query_parameters.iter().filter(|(key, _)| key != "token").cloned().collect(),
+    };
+    let mut metric = Metric::get_metric_off_query_params(&metric_url, &hub.registry)
+        .map_err(|errors| quarantine_rejected(hub, request, HubError::from_validation_errors(errors)))?;
+    // pixels are only ever loaded by browsers and mail clients
+    check_metric_type_allowed(Some(&api_key), &metric)?;
+    metric.enrich(request, get_unix_time_millis(), MetricSource::Website, Some(api_key.id));
+
+    store_metrics(hub, std::slice::from_ref(&metric)).map_err(|e| HubError::Storage(format!("failed to record metric ({})", e)))
+}
+
+// Keeps a copy of an event we refused, so it can be looked at later through GET /admin/quarantine.
+// Hands the error back so it can still be answered with.
+fn quarantine_rejected(hub: &HubContext, request: &HttpRequest, error: HubError) -> HubError {
+    hub.quarantine.add(QuarantinedEvent::get_event_off_request(request, error.get_errors()));
+    error
+}
+
+// GET /admin/quarantine?limit=<n>
+// The most recently refused events, newest first (100 unless limit says otherwise).
+fn handle_quarantine(hub: &HubContext, route: &RouteRequest) -> Result<HttpResponse, HubError> {
+    let limit = route.get_query_param("limit").and_then(|value| value.parse::<usize>().ok()).unwrap_or(100);
+    let (events, total_quarantined) = hub.quarantine.get_recent(limit);
+
+    let body = serde_json::json!({
+        "total_quarantined": total_quarantined,
+        "returned": events.len(),
+        "events": events,
+    });
+
+    Ok(HttpResponse {
+        body: body.to_string().into_bytes(),
+        headers: get_json_headers(),
+        status: StatusCode::Ok
+    })
+}
+
+// The key behind x-ulysses-key, if it may call a route with this access.
+// A missing x-ulysses-key is a 401, one that's there but unknown, retired, expired or without the scope is a 403.
+fn check_ulysses_key<'k>(hub: &HubContext, request: &HttpRequest, api_keys: &'k ApiKeyStore, access: Access) -> Result<&'k ApiKey, HubError> {
+    let presented_key = request.get_header_by_key(String::from("x-ulysses-key"));
+    if presented_key.is_empty() {
+        return Err(HubError::Unauthenticated(String::from("[Error]: Missing ulysses key.")));
+    }
+    if api_keys.is_empty() {
+        return Err(HubError::Config(String::from("[Error]: No ulysses key is configured.")));
+    }
+
+    let config = hub.get_config();
+    let key_match = api_keys
+        .find(&presented_key, config.ulysses_key_pepper.as_deref().unwrap_or(""))
+        .ok_or_else(|| HubError::Forbidden(String::from("[Error]: Invalid ulysses key.")))?;
+    let api_key = key_match.key;
+    let now = get_unix_time_secs();
+
+    // A rotated out generation still gets in until its cutoff. Every use is counted by key and generation
+    // (couch_gag_hub_api_key_requests_total), the log only says so the first time, so a rotation window
+    // doesn't turn into a log line per request.
+    let generation = key_match.generation.get_label();
+    if let KeyGeneration::Previous { valid_until, .. } = key_match.generation {
+        if key_match.generation.is_retired(now) {
+            if hub.stats.record_api_key_use(&api_key.id, generation.clone(), "retired") == 1 {
+                println!(
+                    "[warn]: ulysses key '{}' refused, its {} generation was retired at {} (counted from here on)",
+                    api_key.id, generation, valid_until
+                );
+            }
+            return Err(HubError::Forbidden(format!(
+                "[Error]: Ulysses key '{}' has been rotated, its {} generation is no longer accepted.",
+                api_key.id, generation
+            )));
+        }
+    }
+
+    if api_key.is_expired(now) {
+        hub.stats.record_api_key_use(&api_key.id, generation, "expired");
+        return Err(HubError::Forbidden(format!("[Error]: Ulysses key '{}' has expired.", api_key.id)));
+    }
+    if let Access::Scoped(scope) = access {
+        if !api_key.has_scope(scope) {
+            hub.stats.record_api_key_use(&api_key.id, generation, "missing_scope");
+            return Err(HubError::Forbidden(format!(
+                "[Error]: Ulysses key '{}' does not have the {} scope.",
+                api_key.id,
+                scope.get_name()
+            )));
+        }
+    }
+
+    let uses = hub.stats.record_api_key_use(&api_key.id, generation.clone(), "accepted");
+    if let KeyGeneration::Previous { valid_until, .. } = key_match.generation {
+        if uses == 1 {
+            println!(
+                "[warn]: ulysses key '{}' sent as its {} generation, accepted until {} (counted from here on)",
+                api_key.id, generation, valid_until
+            );
+        }
+    }
+    Ok(api_key)
+}
+
+// Ok when the caller's key may record metrics of this type
+fn check_metric_type_allowed(api_key: Option<&ApiKey>, metric: &Metric) -> Result<(), HubError> {
+    match api_key {
+        Some(api_key) if !api_key.allows_metric_type(&metric.metric_type) => Err(HubError::Forbidden(format!(
+            "[Error]: Ulysses key '{}' may not record {} metrics.",
+            api_key.id,
+            metric.metric_type.get_name()
+        ))),
+        _ => Ok(()),
+    }
+}
+
+// the source a metric gets when the request doesn't name one, the service holding the key if it's one we know
+fn get_default_source(api_key: Option<&ApiKey>) -> MetricSource {
+    api_key
+        .and_then(|api_key| MetricSource::get_source_from_header_value(&api_key.service))
+        .unwrap_or(MetricSource::Unknown)
+}
+
+fn handle_request(http_request_struct_inst: &HttpRequest, hub: &HubContext) -> HttpResponse {
+    let method = http_request_struct_inst.get_http_method();
+    println!("Http Method: {}", &method);
+    println!("Http Req Pathname: {}", &http_request_struct_inst.path);
+
+    // every failure, whatever route it came from, answers with its own status and the {"errors": [...]} body
+    route_request(http_request_struct_inst, hub).unwrap_or_else(HubError::into_response)
+}
+
+fn route_request(http_request_struct_inst: &HttpRequest, hub: &HubContext) -> Result<HttpResponse, HubError> {
+    let req_url_struct_inst = get_url_from_req(http_request_struct_inst);
+    let method = http_request_struct_inst.get_http_method();
+
     // We hit a gnarly bug with preflight requests being for lack of a better word fucked
     // because the browser wasn't attaching x-ulysses-key to the preflight check
     // and I think it just wont (will investigate)
-    // if we hit an options request, we bypass ulysses key, but to perform operations,
-    // we'll run this has_valid_ulysses_key_check before pumping metrics
-    // handle an unauthorized attempt to hit the service
-    if method != "OPTIONS" && !has_valid_ulysses_key(&http_request_struct_inst) {
-        let error = String::from("[Error]: Invalid ulysses key.");
-        errors.push((String::from("CredentialsError"), error));
-    }
-    
-    // WORKING WITH THE RESPONSE 
-    
-    let mut headers_hashmap: HashMap<String, String> = HashMap::new();
-
-    CorsHeaders::add_cors_to_headers(&mut headers_hashmap);
-    ContentHeaders::add_content_type_to_headers(&mut headers_hashmap);
-
-    let mut status_code = 200;
-    let mut body = String::new();
+    // so preflights bypass the ulysses key, they only need the CORS headers and must not record anything
+    if method == "OPTIONS" {
+        let allowed = hub.router.get_allowed_methods(&req_url_struct_inst.path);
+        if allowed.is_empty() {
+            return Err(HubError::NotFound(String::from("[Error] Attempt to access an inaccessible path.")));
+        }
+        let mut headers = get_json_headers();
+        headers.insert(String::from("Allow"), allowed.join(", "));
+        return Ok(HttpResponse {
+            body: vec![],
+            headers,
+            status: StatusCode::Ok
+        });
+    }
 
-    // if we do have errors, reassign status to 500, update body
-    if errors.len() > 0 {
-        let mut error_hashmap: HashMap<String, Vec<(String, String)>> = HashMap::new();
-        error_hashmap.insert(
-            String::from("errors"),
-            errors
-        );
-        status_code = 500;
-        body = serde_json::to_string(&error_hashmap).unwrap_or(String::new())
-    } else {
-        let metric_type = Metric::get_metric_type_off_query_param(&req_url_struct_inst);
-        let metric_subfield = Metric::get_metric_subfield_off_query_params(&req_url_struct_inst);
-        let metric_value = Metric::get_val_off_query_params(&req_url_struct_inst);
-        let metric_target = Metric::get_target_string_off_query_params(&req_url_struct_inst);
-        let metric = Metric::get_metric(metric_type, metric_subfield, metric_target, metric_value);
-        let mut metric_hashmap: HashMap<String, Metric> = HashMap::new();
-        metric_hashmap.insert(
-            String::from("Metric"),
-            metric
-        );
-        body = serde_json::to_string(&metric_hashmap).unwrap_or(String::new());
+    match hub.router.find(&method, &req_url_struct_inst.path) {
+        RouteMatch::Found { route, params } => {
+            // beacons and pixels can't carry the header at all, they authenticate with a beacon token instead
+            let api_keys = hub.get_api_keys();
+            let api_key = match route.access {
+                Access::Public => None,
+                access => {
+                    let checked = check_ulysses_key(hub, http_request_struct_inst, &api_keys, access);
+                    if let Err(e) = &checked {
+                        hub.stats.record_auth_failure("ulysses_key", e);
+                    }
+                    Some(checked?)
+                }
+            };
+
+            let route_request = RouteRequest {
+                request: http_request_struct_inst,
+                url: req_url_struct_inst,
+                params,
+                api_key,
+            };
+            (route.handler)(hub, &route_request)
+        }
+        RouteMatch::MethodNotAllowed(allowed) => Err(HubError::MethodNotAllowed {
+            message: format!("[Error]: {} is not supported here, use {}.", method, allowed.join(", ")),
+            allowed,
+        }),
+        RouteMatch::NotFound => Err(HubError::NotFound(String::from("[Error] Attempt to access an inaccessible path."))),
     }
+}
+
+// the CORS and JSON content type headers nearly every response carries
+fn get_json_headers() -> HashMap<String, String> {
+    let mut headers: HashMap<String, String> = HashMap::new();
+    CorsHeaders::add_cors_to_headers(&mut headers);
+    ContentHeaders::add_content_type_to_headers(&mut headers);
+    headers
+}
+
+// Every route the hub serves. Handlers own their responses, anything they can't do comes back as a HubError.
+fn build_router() -> Router<HubContext> {
+    let mut router: Router<HubContext> = Router::new();
+
+    router.add("GET", "/ping", Access::AnyKey, handle_ping);
+    // for docker-compose healthchecks and the other services, so no key
+    router.add("GET", "/healthz", Access::Public, handle_healthz);
+    router.add("GET", "/readyz", Access::Public, handle_readyz);
+
+    // single metrics, as query params (GET) or a JSON body (POST); "/" is kept for older emitters
+    router.add("GET", "/", Access::Scoped(Scope::Ingest), handle_metric_query);
+    router.add("POST", "/", Access::Scoped(Scope::Ingest), handle_metric_json);
+    router.add("GET", "/metric", Access::Scoped(Scope::Ingest), handle_metric_query);
+    router.add("POST", "/metric", Access::Scoped(Scope::Ingest), handle_metric_json);
+    router.add("POST", "/metrics/batch", Access::Scoped(Scope::Ingest), handle_metric_batch);
+
+    router.add("GET", "/stories/:id/metrics", Access::Scoped(Scope::Query), handle_story_metrics);
+    router.add("GET", "/query", Access::Scoped(Scope::Query), handle_query);
+    router.add("GET", "/metrics/prometheus", Access::Scoped(Scope::Query), handle_prometheus);
+
+    router.add("GET", "/beacon/token", Access::Scoped(Scope::Ingest), handle_beacon_token);
+    router.add("POST", "/beacon", Access::Public, handle_beacon);
+    router.add("GET", "/pixel.gif", Access::Public, handle_pixel);
+
+    router.add("GET", "/admin/quarantine", Access::Scoped(Scope::Admin), handle_quarantine);
+    router.add("GET", "/admin/stats", Access::Scoped(Scope::Admin), handle_stats);
+
+    router
+}
+
+// GET /ping, lets a service check it can reach the hub (and that its key works) without recording anything
+fn handle_ping(_hub: &HubContext, _route: &RouteRequest) -> Result<HttpResponse, HubError> {
+    let body = serde_json::json!({ "status": "ok" });
+
+    Ok(HttpResponse {
+        body: body.to_string().into_bytes(),
+        headers: get_json_headers(),
+        status: StatusCode::Ok
+    })
+}
+
+// GET /healthz
+// Answering at all means the process is alive and a worker is free to serve, that's all this checks.
+fn handle_healthz(hub: &HubContext, _route: &RouteRequest) -> Result<HttpResponse, HubError> {
+    let body = serde_json::json!({
+        "status": "ok",
+        "uptime_secs": hub.started_at.elapsed().as_secs(),
+    });
+
+    Ok(HttpResponse {
+        body: body.to_string().into_bytes(),
+        headers: get_json_headers(),
+        status: StatusCode::Ok
+    })
+}
+
+// GET /readyz
+// Whether the hub should be sent metrics right now: the log can be written, the config it needs is
+// there, and the worker queue has room. Answers 503 with the same body when any of them isn't.
+fn handle_readyz(hub: &HubContext, _route: &RouteRequest) -> Result<HttpResponse, HubError> {
+    let check = |ok: bool| if ok { "ok" } else { "failing" };
+
+    let storage_error = hub.store.check_writable().err().map(|e| e.to_string());
+    let storage = serde_json::json!({
+        "status": check(storage_error.is_none()),
+        "error": storage_error,
+    });
+
+    // without a ulysses key nothing but beacons and pixels can get in
+    let hub_config = hub.get_config();
+    let api_key_count = hub.get_api_keys().get_keys().len();
+    let has_ulysses_key = api_key_count > 0;
+    let has_beacon_secret = hub_config.beacon_secret.is_some();
+    let config = serde_json::json!({
+        "status": check(has_ulysses_key),
+        "ulysses_key": has_ulysses_key,
+        "api_keys": api_key_count,
+        "beacon_ingestion": has_beacon_secret,
+        "metric_types": hub.registry.get_definitions().len(),
+        "strict_metric_types": hub.registry.is_strict(),
+    });
+
+    let queue_saturated = hub.pool_stats.is_saturated();
+    let queue = serde_json::json!({
+        "status": check(!queue_saturated),
+        "queued": hub.pool_stats.get_queued(),
+        "queue_depth": hub.pool_stats.get_queue_depth(),
+        "busy_workers": hub.pool_stats.get_busy(),
+        "workers": hub.pool_stats.get_worker_count(),
+    });
+
+    let shutting_down = hub.shutdown_requested.load(Ordering::SeqCst);
+    let is_ready = storage_error.is_none() && has_ulysses_key && !queue_saturated && !shutting_down;
+
+    let body = serde_json::json!({
+        "status": if is_ready { "ready" } else { "not_ready" },
+        "shutting_down": shutting_down,
+        "checks": {
+            "storage": storage,
+            "config": config,
+            "queue": queue,
+        },
+    });
 
-    let http_response = HttpResponse {
-        body,
-        headers: headers_hashmap,
-        status: status_code
+    Ok(HttpResponse {
+        body: body.to_string().into_bytes(),
+        headers: get_json_headers(),
+        status: if is_ready { StatusCode::Ok } else { StatusCode::ServiceUnavailable }
+    })
+}
+
+// GET /metric?metric=...&target=...
+fn handle_metric_query(hub: &HubContext, route: &RouteRequest) -> Result<HttpResponse, HubError> {
+    let metric_result = Metric::get_metric_off_query_params(&route.url, &hub.registry).map_err(HubError::from_validation_errors);
+    accept_metric(hub, route, metric_result)
+}
+
+// POST /metric with a JSON body
+fn handle_metric_json(hub: &HubContext, route: &RouteRequest) -> Result<HttpResponse, HubError> {
+    let metric_result = get_metric_off_json_request(route.request, &hub.registry);
+    accept_metric(hub, route, metric_result)
+}
+
+fn accept_metric(hub: &HubContext, route: &RouteRequest, metric_result: Result<Metric, HubError>) -> Result<HttpResponse, HubError> {
+    // the client sent something we can't make a metric out of, that's on them
+    let mut metric = metric_result.map_err(|e| quarantine_rejected(hub, route.request, e))?;
+    check_metric_type_allowed(route.api_key, &metric)?;
+    metric.enrich(route.request, get_unix_time_millis(), get_default_source(route.api_key), route.get_key_id());
+
+    record_metric(hub, metric)
+}
+
+// Records the metric before acknowledging it, a client that gets a 200 back
+// should be able to count on the metric being in the log
+fn record_metric(hub: &HubContext, metric: Metric) -> Result<HttpResponse, HubError> {
+    store_metrics(hub, std::slice::from_ref(&metric))
+        .map_err(|e| HubError::Storage(format!("[Error]: Failed to record metric ({}).", e)))?;
+
+    let mut metric_hashmap: HashMap<String, Metric> = HashMap::new();
+    metric_hashmap.insert(
+        String::from("Metric"),
+        metric
+    );
+
+    Ok(HttpResponse {
+        body: serde_json::to_string(&metric_hashmap).unwrap_or_default().into_bytes(),
+        headers: get_json_headers(),
+        status: StatusCode::Ok
+    })
+}
+
+// Every write to the store goes through here, so what's counted for Prometheus is exactly what's in the log.
+// More than one metric is written as a batch, all or nothing.
+fn store_metrics(hub: &HubContext, metrics: &[Metric]) -> std::io::Result<()> {
+    let write_started = Instant::now();
+    let (operation, written) = match metrics {
+        [metric] => ("append", hub.store.append(metric)),
+        _ => ("append_batch", hub.store.append_batch(metrics)),
+    };
+    hub.stats.record_storage_write(operation, write_started.elapsed(), written.is_ok());
+    written?;
+
+    metrics.iter().for_each(|metric| hub.aggregator.record(metric));
+    hub.recent_metrics.push_all(metrics);
+    Ok(())
+}
+
+// GET /admin/stats
+// How the hub itself is doing, the same numbers the couch_gag_hub_* Prometheus families carry.
+fn handle_stats(hub: &HubContext, _route: &RouteRequest) -> Result<HttpResponse, HubError> {
+    let mut body = hub.stats.to_json(&hub.pool_stats);
+    body["uptime_secs"] = serde_json::json!(hub.started_at.elapsed().as_secs());
+
+    Ok(HttpResponse {
+        body: body.to_string().into_bytes(),
+        headers: get_json_headers(),
+        status: StatusCode::Ok
+    })
+}
+
+// GET /metrics/prometheus
+// Event counts and value sums per metric type, target and tags for a Prometheus scrape,
+// followed by the hub's own stats (see GET /admin/stats).
+// Prometheus gets the text format unless its Accept header asks for OpenMetrics. The route takes
+// the ulysses key like any other, the scrape config sends it with http_headers.
+fn handle_prometheus(hub: &HubContext, route: &RouteRequest) -> Result<HttpResponse, HubError> {
+    let format = ExpositionFormat::from_accept_header(&route.request.get_header_by_key(String::from("Accept")));
+
+    let mut body = String::new();
+    hub.aggregator.write_families(&mut body, format);
+    hub.stats.write_families(&mut body, format, &hub.pool_stats);
+    exposition::finish(&mut body, format);
+
+    let mut headers: HashMap<String, String> = HashMap::new();
+    CorsHeaders::add_cors_to_headers(&mut headers);
+    ContentHeaders::add_content_type_to_headers_as(&mut headers, format.get_content_type());
+
+    Ok(HttpResponse {
+        body: body.into_bytes(),
+        headers,
+        status: StatusCode::Ok
+    })
+}
+
+// GET /stories/:id/metrics?metric=<wire key>&tag.<key>=<value>&limit=<n>
+// What's been recorded against one story (metrics whose target is the story id), newest first.
+fn handle_story_metrics(hub: &HubContext, route: &RouteRequest) -> Result<HttpResponse, HubError> {
+    let story_id = route.get_param("id").unwrap_or_default();
+
+    let mut filter = MetricFilter {
+        target: Some(String::from(story_id)),
+        ..MetricFilter::default()
     };
+    if let Some(wire_key) = route.get_query_param("metric") {
+        let metric_type = hub.registry.get_metric_type_from_wire_key(wire_key).ok_or_else(|| {
+            HubError::Validation(vec![(
+                String::from("ValidationError"),
+                format!("metric: '{}' is not a known metric type", wire_key),
+            )])
+        })?;
+        filter.metric_type = Some(metric_type);
+    }
+    filter.tags = Metric::get_tags_off_query_params(&route.url).map_err(HubError::Validation)?;
+
+    let limit = route.get_query_param("limit").and_then(|value| value.parse::<usize>().ok()).unwrap_or(100);
+
+    let metrics = hub.recent_metrics.find(&filter);
+    let total = metrics.len();
+    let newest: Vec<&Metric> = metrics.iter().rev().take(limit).collect();
+
+    let body = serde_json::json!({
+        "story": story_id,
+        "total": total,
+        "returned": newest.len(),
+        "metrics": newest,
+    });
+
+    Ok(HttpResponse {
+        body: body.to_string().into_bytes(),
+        headers: get_json_headers(),
+        status: StatusCode::Ok
+    })
+}
+
+// GET /query?metric=...&target=...&tag.<key>=...&from=...&to=...&aggregation=...&group_by=...&interval=...
+// A time series aggregated from the recorded metrics, see MetricQuery for the params.
+fn handle_query(hub: &HubContext, route: &RouteRequest) -> Result<HttpResponse, HubError> {
+    let query = MetricQuery::from_route(route, &hub.registry).map_err(HubError::Validation)?;
 
-    let response = http_response.build();
+    let metrics = hub.recent_metrics.find(&query.filter);
 
-    stream.write(response.as_bytes()).unwrap();
-    stream.flush().unwrap();
+    Ok(HttpResponse {
+        body: query.run(&metrics).to_string().into_bytes(),
+        headers: get_json_headers(),
+        status: StatusCode::Ok
+    })
 }
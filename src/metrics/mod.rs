@@ -1,9 +1,40 @@
 pub mod metric {
 
+    use crate::http_request::http_request_base_kit::HttpRequest;
+    use crate::registry::metric_registry::MetricRegistry;
     use crate::url::url::ReqUrl;
-    use serde::{Serialize, Serializer, ser::SerializeStruct};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer, ser::SerializeStruct};
+    use serde_json::{Map, Value};
+    use std::collections::BTreeMap;
+    use std::convert::TryFrom;
 
-    #[derive(Serialize)]
+    // every key a JSON metric body may carry, anything else is a validation error
+    const METRIC_JSON_FIELDS: [&str; 8] =
+        ["metric_type", "subfield", "target", "value", "value_kind", "tags", "extras", "occurred_at"];
+
+    // Tags are what metrics get sliced by (genre, device class, theme, experiment variant), so they're
+    // kept small and predictable: a handful of short keys from a plain alphabet, with bounded values.
+    pub const MAX_TAGS_PER_METRIC: usize = 16;
+    pub const MAX_TAG_KEY_LENGTH: usize = 64;
+    pub const MAX_TAG_VALUE_LENGTH: usize = 128;
+
+    // query params / form fields prefixed with this are tags, ?tag.genre=horror
+    const TAG_QUERY_PARAM_PREFIX: &str = "tag.";
+
+    // Client clocks can't be trusted. An occurred_at later than the moment we received the metric is
+    // pulled back to received_at, and one further in the past than this is pulled forward to the limit
+    // (a day leaves room for buffered events flushed late, e.g. on the next visit).
+    const MAX_OCCURRED_AT_AGE_MS: u64 = 24 * 60 * 60 * 1000;
+
+    // the most metrics a single batch request may carry
+    pub const MAX_BATCH_SIZE: usize = 500;
+
+    // a metric, or every (error kind, message) pair explaining why one couldn't be built
+    pub type MetricResult = Result<Metric, Vec<(String, String)>>;
+
+    // The six built in types, plus whatever the metric registry file defines (see registry).
+    // Recorded as its name, "StoryView" or e.g. "ScrollDepth" for a type from the registry file.
+    #[derive(Clone, PartialEq, Eq, Hash, Debug)]
     pub enum MetricName {
         StoryView,
         PageView,
@@ -11,13 +42,255 @@ pub mod metric {
         Share,
         Error,
         Base,
+        Custom(String),
+    }
+
+    impl MetricName {
+        pub fn get_name(&self) -> String {
+            match self {
+                MetricName::StoryView => String::from("StoryView"),
+                MetricName::PageView => String::from("PageView"),
+                MetricName::ButtonClick => String::from("ButtonClick"),
+                MetricName::Share => String::from("Share"),
+                MetricName::Error => String::from("Error"),
+                MetricName::Base => String::from("Base"),
+                MetricName::Custom(name) => name.clone(),
+            }
+        }
+
+        pub fn from_name(name: &str) -> MetricName {
+            match name {
+                "StoryView" => MetricName::StoryView,
+                "PageView" => MetricName::PageView,
+                "ButtonClick" => MetricName::ButtonClick,
+                "Share" => MetricName::Share,
+                "Error" => MetricName::Error,
+                "Base" => MetricName::Base,
+                custom => MetricName::Custom(String::from(custom)),
+            }
+        }
+    }
+
+    impl Serialize for MetricName {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            serializer.serialize_str(&self.get_name())
+        }
+    }
+
+    impl<'de> Deserialize<'de> for MetricName {
+        fn deserialize<D>(deserializer: D) -> Result<MetricName, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            let name = String::deserialize(deserializer)?;
+            Ok(MetricName::from_name(&name))
+        }
+    }
+
+    // which Couch Gag service emitted a metric
+    #[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+    #[serde(rename_all = "kebab-case")]
+    pub enum MetricSource {
+        Website,
+        Server,
+        #[default]
+        Unknown,
+    }
+
+    impl MetricSource {
+        // services identify themselves with the x-couch-gag-source header
+        pub fn get_source_from_header_value(value: &str) -> Option<MetricSource> {
+            match value.trim().to_ascii_lowercase().as_str() {
+                "couch-gag-website" | "website" => Some(MetricSource::Website),
+                "couch-gag-server" | "server" => Some(MetricSource::Server),
+                _ => None,
+            }
+        }
+    }
+
+    // What a metric measures, on the wire as a number plus a "value_kind" naming how to read it.
+    //
+    //   counter   a whole number of things that happened (clicks, views), may be negative for corrections
+    //   gauge     a point in time reading that can be fractional (scroll depth 0.75)
+    //   duration  a span of time, kept in milliseconds; clients may send "250ms", "1.5s" or "2m"
+    #[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+    #[serde(try_from = "RawMetricValue", into = "RawMetricValue")]
+    pub enum MetricValue {
+        Counter(i64),
+        Gauge(f64),
+        Duration(u64),
+    }
+
+    // the on-disk / on-the-wire shape of a MetricValue
+    #[derive(Serialize, Deserialize)]
+    struct RawMetricValue {
+        value: Value,
+        // lines logged before value kinds existed have none, they were all counters
+        #[serde(default)]
+        value_kind: Option<String>,
+    }
+
+    impl From<MetricValue> for RawMetricValue {
+        fn from(metric_value: MetricValue) -> RawMetricValue {
+            let value = match metric_value {
+                MetricValue::Counter(count) => Value::from(count),
+                MetricValue::Gauge(reading) => Value::from(reading),
+                MetricValue::Duration(millis) => Value::from(millis),
+            };
+            RawMetricValue {
+                value,
+                value_kind: Some(String::from(metric_value.get_kind())),
+            }
+        }
+    }
+
+    impl TryFrom<RawMetricValue> for MetricValue {
+        type Error = String;
+
+        fn try_from(raw: RawMetricValue) -> Result<MetricValue, String> {
+            MetricValue::get_value_off_json(&raw.value, raw.value_kind.as_deref())
+        }
     }
 
+    impl Default for MetricValue {
+        fn default() -> MetricValue {
+            MetricValue::Counter(0)
+        }
+    }
+
+    impl MetricValue {
+        pub fn get_kind(&self) -> &'static str {
+            match self {
+                MetricValue::Counter(_) => "counter",
+                MetricValue::Gauge(_) => "gauge",
+                MetricValue::Duration(_) => "duration",
+            }
+        }
+
+        // durations come out in milliseconds
+        pub fn as_f64(&self) -> f64 {
+            match self {
+                MetricValue::Counter(count) => *count as f64,
+                MetricValue::Gauge(reading) => *reading,
+                MetricValue::Duration(millis) => *millis as f64,
+            }
+        }
+
+        // What a metric gets when the client leaves the value out. Only counters have a sensible
+        // default (0, same as it has always been), a gauge or duration with no reading means nothing.
+        pub fn get_default_for_kind(kind: Option<&str>) -> Result<MetricValue, String> {
+            match kind {
+                None | Some("counter") => Ok(MetricValue::default()),
+                Some(kind) => Err(format!("is required for {} metrics", kind)),
+            }
+        }
+
+        // Reads a value from its text form (query params, form fields).
+        // Without a kind, "12" is a counter, "0.5" a gauge and "250ms" a duration.
+        pub fn get_value_off_str(raw: &str, kind: Option<&str>) -> Result<MetricValue, String> {
+            let raw = raw.trim();
+            match kind {
+                None => {
+                    if let Ok(count) = raw.parse::<i64>() {
+                        return Ok(MetricValue::Counter(count));
+                    }
+                    MetricValue::get_gauge_off_str(raw)
+                        .or_else(|_| MetricValue::get_duration_off_str(raw))
+                        .map_err(|_| format!("'{}' is not a number, or a duration such as 250ms", raw))
+                }
+                Some("counter") => raw
+                    .parse::<i64>()
+                    .map(MetricValue::Counter)
+                    .map_err(|_| format!("'{}' is not a whole number counter", raw)),
+                Some("gauge") => MetricValue::get_gauge_off_str(raw),
+                Some("duration") => MetricValue::get_duration_off_str(raw),
+                Some(other) => Err(format!("'{}' is not a value kind, expected counter, gauge or duration", other)),
+            }
+        }
+
+        // Reads a value from a JSON body, numbers are counters when whole and gauges otherwise,
+        // strings are durations. An explicit kind overrides the guess.
+        pub fn get_value_off_json(raw: &Value, kind: Option<&str>) -> Result<MetricValue, String> {
+            match (raw, kind) {
+                (Value::Number(n), None) => match n.as_i64() {
+                    Some(count) => Ok(MetricValue::Counter(count)),
+                    None => MetricValue::get_gauge_off_str(&n.to_string()),
+                },
+                (Value::Number(n), Some(_)) => MetricValue::get_value_off_str(&n.to_string(), kind),
+                (Value::String(s), None) => MetricValue::get_duration_off_str(s),
+                (Value::String(s), Some(_)) => MetricValue::get_value_off_str(s, kind),
+                _ => Err(String::from("must be a number, or a duration string such as \"250ms\"")),
+            }
+        }
+
+        fn get_gauge_off_str(raw: &str) -> Result<MetricValue, String> {
+            match raw.parse::<f64>() {
+                Ok(reading) if reading.is_finite() => Ok(MetricValue::Gauge(reading)),
+                _ => Err(format!("'{}' is not a number", raw)),
+            }
+        }
+
+        // "250ms", "1.5s", "2m", "1h", "7d", or a bare number of milliseconds
+        fn get_duration_off_str(raw: &str) -> Result<MetricValue, String> {
+            let raw = raw.trim();
+            let (amount, millis_per_unit) = if let Some(amount) = raw.strip_suffix("ms") {
+                (amount, 1.0)
+            } else if let Some(amount) = raw.strip_suffix('s') {
+                (amount, 1000.0)
+            } else if let Some(amount) = raw.strip_suffix('m') {
+                (amount, 60_000.0)
+            } else if let Some(amount) = raw.strip_suffix('h') {
+                (amount, 3_600_000.0)
+            } else if let Some(amount) = raw.strip_suffix('d') {
+                (amount, 86_400_000.0)
+            } else {
+                (raw, 1.0)
+            };
+
+            match amount.trim().parse::<f64>() {
+                Ok(amount) if amount.is_finite() && amount >= 0.0 => {
+                    Ok(MetricValue::Duration((amount * millis_per_unit).round() as u64))
+                }
+                _ => Err(format!("'{}' is not a duration, expected e.g. 250ms, 1.5s, 2m or 1h", raw)),
+            }
+        }
+    }
+
+    #[derive(Deserialize, Clone)]
     pub struct Metric {
         pub metric_type: MetricName,
         pub subfield: String,
         pub target: String,
-        pub value: u8,
+        #[serde(flatten)]
+        pub value: MetricValue,
+        // dimensions to slice by, see MAX_TAGS_PER_METRIC and friends for the limits
+        #[serde(default)]
+        pub tags: BTreeMap<String, String>,
+        // free-form, client supplied context that rides along with the metric
+        #[serde(default)]
+        pub extras: Option<Value>,
+
+        // everything below is filled in by the hub (see enrich), not trusted from clients.
+        // Defaults keep metric log lines written before these fields existed readable.
+
+        // unix millis, by the hub's clock
+        #[serde(default)]
+        pub received_at: u64,
+        // unix millis, by the client's clock, clamped against received_at
+        #[serde(default)]
+        pub occurred_at: Option<u64>,
+        #[serde(default)]
+        pub source: MetricSource,
+        #[serde(default)]
+        pub remote_addr: Option<String>,
+        #[serde(default)]
+        pub user_agent: Option<String>,
+        // the id of the api key it was sent with, beacons and pixels come with a token instead and have none
+        #[serde(default)]
+        pub key_id: Option<String>,
     }
 
     impl Serialize for Metric {
@@ -25,34 +298,362 @@ pub mod metric {
         where
             S: Serializer,
         {
-            let mut state = serializer.serialize_struct("Metric", 4)?;
+            let mut state = serializer.serialize_struct("Metric", 13)?;
             state.serialize_field("metric_type", &self.metric_type)?;
             state.serialize_field("subfield", &self.subfield)?;
             state.serialize_field("target", &self.target)?;
-            state.serialize_field("value", &self.value)?;
+            let raw_value = RawMetricValue::from(self.value);
+            state.serialize_field("value", &raw_value.value)?;
+            state.serialize_field("value_kind", &raw_value.value_kind)?;
+            state.serialize_field("tags", &self.tags)?;
+            match &self.extras {
+                Some(extras) => state.serialize_field("extras", extras)?,
+                None => state.skip_field("extras")?,
+            }
+            state.serialize_field("received_at", &self.received_at)?;
+            state.serialize_field("occurred_at", &self.occurred_at)?;
+            state.serialize_field("source", &self.source)?;
+            state.serialize_field("remote_addr", &self.remote_addr)?;
+            state.serialize_field("user_agent", &self.user_agent)?;
+            state.serialize_field("key_id", &self.key_id)?;
             state.end()
         }
     }
 
     impl Metric {
-        pub fn get_metric(m_type: MetricName, s: String, t: String, v: u8) -> Metric {
+        pub fn get_metric(m_type: MetricName, s: String, t: String, v: MetricValue) -> Metric {
             Metric {
                 metric_type: m_type,
                 subfield: s,
                 target: t,
-                value: v
+                value: v,
+                tags: BTreeMap::new(),
+                extras: None,
+                received_at: 0,
+                occurred_at: None,
+                source: MetricSource::Unknown,
+                remote_addr: None,
+                user_agent: None,
+                key_id: None,
+            }
+        }
+
+        // builds a metric out of query params (or form fields, which share the encoding)
+        pub fn get_metric_off_query_params(req_url: &ReqUrl, registry: &MetricRegistry) -> MetricResult {
+            let metric_type = match Metric::get_metric_type_off_query_param(req_url, registry) {
+                Ok(metric_type) => metric_type,
+                Err(message) => {
+                    return Err(vec![(String::from("ValidationError"), format!("metric: {}", message))]);
+                }
+            };
+            let metric_subfield = Metric::get_metric_subfield_off_query_params(req_url);
+            let registered_kind = registry.get_value_kind(&metric_type);
+            let metric_value = match Metric::get_val_off_query_params(req_url, registered_kind) {
+                Ok(metric_value) => metric_value,
+                Err(message) => {
+                    return Err(vec![(String::from("ValidationError"), format!("value: {}", message))]);
+                }
+            };
+            let metric_target = Metric::get_target_string_off_query_params(req_url);
+            let metric_tags = Metric::get_tags_off_query_params(req_url)?;
+
+            let mut metric = Metric::get_metric(metric_type, metric_subfield, metric_target, metric_value);
+            metric.tags = metric_tags;
+            metric.occurred_at = Metric::get_occurred_at_off_query_params(req_url);
+            Metric::check_against_registry(metric, registry)
+        }
+
+        // the metric if it fits its registered type, every way it doesn't otherwise
+        fn check_against_registry(metric: Metric, registry: &MetricRegistry) -> MetricResult {
+            let problems = registry.validate(&metric);
+            if problems.is_empty() {
+                Ok(metric)
+            } else {
+                Err(problems
+                    .into_iter()
+                    .map(|(field, message)| (String::from("ValidationError"), format!("{}: {}", field, message)))
+                    .collect())
+            }
+        }
+
+        // every ?tag.<key>=<value> pair, validated against the tag limits
+        pub fn get_tags_off_query_params(req_url: &ReqUrl) -> Result<BTreeMap<String, String>, Vec<(String, String)>> {
+            let mut tags: BTreeMap<String, String> = BTreeMap::new();
+            for (key, value) in req_url.query_parameters.iter() {
+                if let Some(tag_key) = key.strip_prefix(TAG_QUERY_PARAM_PREFIX) {
+                    tags.insert(String::from(tag_key), value.clone());
+                }
+            }
+
+            let tag_errors = Metric::validate_tags(&tags);
+            if tag_errors.is_empty() {
+                Ok(tags)
+            } else {
+                Err(tag_errors
+                    .into_iter()
+                    .map(|(field, message)| (String::from("ValidationError"), format!("{}: {}", field, message)))
+                    .collect())
+            }
+        }
+
+        // (field, message) for every way a tag map breaks the limits, empty when it's fine
+        pub fn validate_tags(tags: &BTreeMap<String, String>) -> Vec<(String, String)> {
+            let mut problems: Vec<(String, String)> = Vec::new();
+
+            if tags.len() > MAX_TAGS_PER_METRIC {
+                problems.push((
+                    String::from("tags"),
+                    format!("at most {} tags are allowed, got {}", MAX_TAGS_PER_METRIC, tags.len()),
+                ));
+            }
+
+            for (key, value) in tags.iter() {
+                let field = format!("tags.{}", key);
+                let is_valid_key = !key.is_empty()
+                    && key.len() <= MAX_TAG_KEY_LENGTH
+                    && key.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-');
+                if !is_valid_key {
+                    problems.push((
+                        field.clone(),
+                        format!(
+                            "tag keys must be 1 to {} characters of letters, digits, '_' or '-'",
+                            MAX_TAG_KEY_LENGTH
+                        ),
+                    ));
+                }
+                if value.is_empty() || value.chars().count() > MAX_TAG_VALUE_LENGTH {
+                    problems.push((field, format!("tag values must be 1 to {} characters", MAX_TAG_VALUE_LENGTH)));
+                }
+            }
+
+            problems
+        }
+
+        // Stamps a metric with what the hub knows about how it arrived.
+        // default_source applies when the client didn't identify itself with x-couch-gag-source.
+        pub fn enrich(&mut self, request: &HttpRequest, received_at: u64, default_source: MetricSource, key_id: Option<String>) {
+            self.received_at = received_at;
+            self.key_id = key_id;
+            self.occurred_at = self.occurred_at.map(|occurred_at| {
+                occurred_at
+                    .min(received_at)
+                    .max(received_at.saturating_sub(MAX_OCCURRED_AT_AGE_MS))
+            });
+
+            let source_header = request.get_header_by_key(String::from("x-couch-gag-source"));
+            self.source = MetricSource::get_source_from_header_value(&source_header).unwrap_or(default_source);
+
+            self.remote_addr = request.remote_addr.clone();
+
+            let user_agent = request.get_header_by_key(String::from("User-Agent"));
+            self.user_agent = if user_agent.is_empty() { None } else { Some(user_agent) };
+        }
+
+        // when the metric happened, as best we know: the client's clock if it said, ours otherwise
+        pub fn get_event_time(&self) -> u64 {
+            self.occurred_at.unwrap_or(self.received_at)
+        }
+
+        // Validates a JSON request body against the metric schema,
+        // { "metric_type": "story-view", "subfield": "...", "target": "...", "value": 1, "extras": { ... } }
+        // metric_type is required, everything else falls back to the same defaults as the query param route.
+        // Every problem found is reported, not just the first, as ("ValidationError", "<field>: <message>").
+        // A body that isn't JSON at all is a ("ParseError", ...) instead.
+        pub fn get_metric_off_json_body(body: &str, registry: &MetricRegistry) -> MetricResult {
+            match serde_json::from_str::<Value>(body) {
+                Ok(parsed) => Metric::get_metric_off_json_value(parsed, registry),
+                Err(e) => Err(vec![(
+                    String::from("ParseError"),
+                    format!("body: is not valid JSON ({})", e),
+                )]),
+            }
+        }
+
+        // Splits a batch body into its items and validates each one on its own.
+        // A batch is either a JSON array of metric objects, or NDJSON (one metric object per line).
+        // The outer Err is for a batch we can't even split up, the inner results line up with the items.
+        pub fn get_metrics_off_batch_body(
+            body: &str,
+            is_ndjson: bool,
+            registry: &MetricRegistry,
+        ) -> Result<Vec<MetricResult>, Vec<(String, String)>> {
+            let batch_error = |message: String| vec![(String::from("ValidationError"), message)];
+            let oversize_error = || {
+                vec![(
+                    String::from("PayloadTooLargeError"),
+                    format!("body: a batch holds at most {} metrics", MAX_BATCH_SIZE),
+                )]
+            };
+
+            let items: Vec<MetricResult> = if is_ndjson {
+                body.lines()
+                    .filter(|line| !line.trim().is_empty())
+                    .take(MAX_BATCH_SIZE + 1)
+                    .map(|line| Metric::get_metric_off_json_body(line, registry))
+                    .collect()
+            } else {
+                match serde_json::from_str::<Value>(body) {
+                    Ok(Value::Array(values)) => {
+                        if values.len() > MAX_BATCH_SIZE {
+                            return Err(oversize_error());
+                        }
+                        values
+                            .into_iter()
+                            .map(|value| Metric::get_metric_off_json_value(value, registry))
+                            .collect()
+                    }
+                    Ok(_) => return Err(batch_error(String::from("body: must be a JSON array of metrics"))),
+                    Err(e) => return Err(vec![(String::from("ParseError"), format!("body: is not valid JSON ({})", e))]),
+                }
+            };
+
+            if items.is_empty() {
+                return Err(batch_error(String::from("body: batch contains no metrics")));
+            }
+            if items.len() > MAX_BATCH_SIZE {
+                return Err(oversize_error());
+            }
+
+            Ok(items)
+        }
+
+        pub fn get_metric_off_json_value(parsed: Value, registry: &MetricRegistry) -> MetricResult {
+            let mut errors: Vec<(String, String)> = Vec::new();
+            let mut field_error = |field: &str, message: &str| {
+                errors.push((String::from("ValidationError"), format!("{}: {}", field, message)));
+            };
+
+            let fields: Map<String, Value> = match parsed {
+                Value::Object(fields) => fields,
+                _ => {
+                    field_error("body", "must be a JSON object");
+                    return Err(errors);
+                }
+            };
+
+            for key in fields.keys() {
+                if !METRIC_JSON_FIELDS.contains(&key.as_str()) {
+                    field_error(key, "is not a recognised metric field");
+                }
+            }
+
+            let metric_type = match fields.get("metric_type") {
+                Some(Value::String(wire_key)) => match registry.get_metric_type_from_wire_key(wire_key) {
+                    Some(metric_type) => Some(metric_type),
+                    None => {
+                        field_error("metric_type", &format!("'{}' is not a known metric type", wire_key));
+                        None
+                    }
+                },
+                Some(_) => {
+                    field_error("metric_type", "must be a string");
+                    None
+                }
+                None => {
+                    field_error("metric_type", "is required");
+                    None
+                }
+            };
+
+            let mut get_string_field = |field: &str| -> String {
+                match fields.get(field) {
+                    Some(Value::String(s)) => s.clone(),
+                    None | Some(Value::Null) => String::new(),
+                    Some(_) => {
+                        field_error(field, "must be a string");
+                        String::new()
+                    }
+                }
+            };
+            let subfield = get_string_field("subfield");
+            let target = get_string_field("target");
+
+            let value_kind = match fields.get("value_kind") {
+                None | Some(Value::Null) => None,
+                Some(Value::String(kind)) => Some(kind.as_str()),
+                Some(_) => {
+                    field_error("value_kind", "must be a string");
+                    None
+                }
+            };
+
+            let registered_kind = metric_type.as_ref().and_then(|metric_type| registry.get_value_kind(metric_type));
+            let value = match fields.get("value") {
+                None | Some(Value::Null) => match value_kind {
+                    None => MetricValue::get_default_for_kind(registered_kind),
+                    Some(_) => Err(String::from("is required when value_kind is given")),
+                },
+                Some(raw) => MetricValue::get_value_off_json(raw, value_kind.or(registered_kind)),
+            };
+            let value = value.unwrap_or_else(|message| {
+                field_error("value", &message);
+                MetricValue::default()
+            });
+
+            let occurred_at = match fields.get("occurred_at") {
+                None | Some(Value::Null) => None,
+                Some(Value::Number(n)) => match n.as_u64() {
+                    Some(occurred_at) => Some(occurred_at),
+                    None => {
+                        field_error("occurred_at", "must be a whole number of milliseconds since the unix epoch");
+                        None
+                    }
+                },
+                Some(_) => {
+                    field_error("occurred_at", "must be a number");
+                    None
+                }
+            };
+
+            let mut tags: BTreeMap<String, String> = BTreeMap::new();
+            match fields.get("tags") {
+                None | Some(Value::Null) => {}
+                Some(Value::Object(raw_tags)) => {
+                    for (key, value) in raw_tags.iter() {
+                        match value {
+                            Value::String(value) => {
+                                tags.insert(key.clone(), value.clone());
+                            }
+                            _ => field_error(&format!("tags.{}", key), "must be a string"),
+                        }
+                    }
+                    for (field, message) in Metric::validate_tags(&tags) {
+                        field_error(&field, &message);
+                    }
+                }
+                Some(_) => field_error("tags", "must be a JSON object of string values"),
+            }
+
+            let extras = match fields.get("extras") {
+                None | Some(Value::Null) => None,
+                Some(Value::Object(extras)) => Some(Value::Object(extras.clone())),
+                Some(_) => {
+                    field_error("extras", "must be a JSON object");
+                    None
+                }
+            };
+
+            match metric_type {
+                Some(metric_type) if errors.is_empty() => {
+                    let mut metric = Metric::get_metric(metric_type, subfield, target, value);
+                    metric.tags = tags;
+                    metric.extras = extras;
+                    metric.occurred_at = occurred_at;
+                    Metric::check_against_registry(metric, registry)
+                }
+                _ => Err(errors),
             }
         }
 
         pub fn get_metric_type_as_string(m_type: MetricName) -> String {
             let metric_type_string: String = match m_type {
+                MetricName::Custom(name) => format!("couch-gag-{}", name),
                 MetricName::Base => String::from("couch-gag-base-metric-health-ping"),
                 MetricName::ButtonClick => String::from("couch-gag-button-click"),
                 MetricName::Error => String::from("couch-gag-error"),
                 MetricName::PageView => String::from("couch-gag-page-view-hit"),
                 MetricName::Share => String::from("couch-gag-share-story"),
                 MetricName::StoryView => String::from("couch-gag-story-view"),
-                _ => String::from("couch-gag-missed-metric-type")
             };
 
             metric_type_string
@@ -61,57 +662,72 @@ pub mod metric {
         pub fn get_metric_subfield_off_query_params(req_url: &ReqUrl) -> String {
             let mut msg = String::new();
             for req_param in req_url.query_parameters.clone() {
-                if req_param.0.contains("subfield") {
-                    msg = String::from(req_param.1);
+                if req_param.0 == "subfield" {
+                    msg = req_param.1;
                 }
             }
             msg
         }
 
-        pub fn get_val_off_query_params(req_url: &ReqUrl) -> u8 {
-            let mut val = 0;
-            for req_param in req_url.query_parameters.clone() {
-                if req_param.0.contains("value") {
-                    val = req_param.1.parse::<u8>().unwrap();
-                }
+        // ?value=12, ?value=0.75, ?value=250ms, with an optional ?value_kind=counter|gauge|duration
+        // registered_kind is how the metric's type says to read the value when the client doesn't say.
+        // no value at all is a counter of 0, same as it has always been
+        pub fn get_val_off_query_params(req_url: &ReqUrl, registered_kind: Option<&str>) -> Result<MetricValue, String> {
+            let find_param = |name: &str| {
+                req_url
+                    .query_parameters
+                    .iter()
+                    .rev()
+                    .find(|(key, _)| key == name)
+                    .map(|(_, value)| value.as_str())
+            };
+
+            let kind = find_param("value_kind");
+            match find_param("value") {
+                Some(raw) => MetricValue::get_value_off_str(raw, kind.or(registered_kind)),
+                None if kind.is_some() => Err(String::from("is required when value_kind is given")),
+                None => MetricValue::get_default_for_kind(registered_kind),
             }
-            val
+        }
+
+        // unix millis, anything that isn't a whole number is ignored
+        pub fn get_occurred_at_off_query_params(req_url: &ReqUrl) -> Option<u64> {
+            req_url
+                .query_parameters
+                .iter()
+                .find(|(key, _)| key == "occurred_at")
+                .and_then(|(_, value)| value.parse::<u64>().ok())
         }
 
         pub fn get_target_string_off_query_params(req_url: &ReqUrl) -> String {
             let mut target = String::new();
             for req_param in req_url.query_parameters.clone() {
-                if req_param.0.contains("target") {
+                if req_param.0 == "target" {
                     target = req_param.1
                 }
             }
             target
         }
     
-        pub fn get_metric_type_off_query_param(req_url: &ReqUrl) -> MetricName {
-            let mut metric_type: MetricName = MetricName::Base;
-    
-            for req_param in req_url.query_parameters.clone() {
-                if req_param.0.contains("metric") {
-                    metric_type = {
-                        if req_param.1.eq("story-view") {
-                            MetricName::StoryView
-                        } else if req_param.1.eq("page-view") {
-                            MetricName::PageView
-                        } else if req_param.1.eq("share") {
-                            MetricName::Share
-                        } else if req_param.1.eq("button-click") {
-                            MetricName::ButtonClick
-                        } else if req_param.1.eq("base") {
-                            MetricName::Base
-                        } else {
-                            MetricName::Error
-                        }
-                    }
-                }
+        // ?metric=<wire key>. In lenient mode a missing key is Base and one the registry doesn't know
+        // is Error, in strict mode both are an Err naming the problem.
+        pub fn get_metric_type_off_query_param(req_url: &ReqUrl, registry: &MetricRegistry) -> Result<MetricName, String> {
+            let wire_key = req_url
+                .query_parameters
+                .iter()
+                .rev()
+                .find(|(key, _)| key == "metric")
+                .map(|(_, value)| value.as_str());
+
+            match wire_key {
+                Some(wire_key) => match registry.get_metric_type_from_wire_key(wire_key) {
+                    Some(metric_type) => Ok(metric_type),
+                    None if registry.is_strict() => Err(format!("'{}' is not a known metric type", wire_key)),
+                    None => Ok(MetricName::Error),
+                },
+                None if registry.is_strict() => Err(String::from("is required")),
+                None => Ok(MetricName::Base),
             }
-    
-            metric_type
         }
     }
 }
@@ -0,0 +1,457 @@
+pub mod prometheus {
+
+    // Running totals of everything ingested, rendered for a Prometheus scrape (GET /metrics/prometheus).
+    //
+    // Series are keyed by metric type, target and tags. target, tag keys and tag values are all
+    // whatever clients send (story slugs, page paths...), so all of them are capped: once a metric type
+    // has seen max_targets distinct targets, new ones are counted under target="__other__", same for a
+    // tag key's values. Past max_tag_keys distinct tag keys, new keys (and keys whose label name is
+    // already another key's, "a-b" next to "a_b") all go out as the one tag___other__="__other__" label.
+    // Past max_series every new combination folds into one overflow series per type.
+
+    use crate::metrics::metric::Metric;
+    use std::collections::{BTreeMap, HashMap, HashSet};
+    use std::sync::Mutex;
+
+    // what labels over the caps are folded into
+    pub const OTHER_LABEL_VALUE: &str = "__other__";
+    // the label tag keys over the cap are folded into, what get_tag_label_name makes of "__other__"
+    const OTHER_TAG_LABEL_NAME: &str = "tag___other__";
+
+    // target labels are cut to this many characters before they count against the cap
+    const MAX_TARGET_LABEL_LENGTH: usize = 128;
+
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    pub enum ExpositionFormat {
+        // the classic text format, version 0.0.4
+        Text,
+        OpenMetrics,
+    }
+
+    impl ExpositionFormat {
+        // OpenMetrics only when the scraper asks for it, Prometheus does by default
+        pub fn from_accept_header(accept: &str) -> ExpositionFormat {
+            let wants_open_metrics = accept.split(',').any(|media_range| {
+                let mut parts = media_range.split(';').map(|part| part.trim());
+                let media_type = parts.next().unwrap_or("");
+                let refused = parts.any(|param| param.replace(' ', "") == "q=0");
+                media_type.eq_ignore_ascii_case("application/openmetrics-text") && !refused
+            });
+
+            if wants_open_metrics {
+                ExpositionFormat::OpenMetrics
+            } else {
+                ExpositionFormat::Text
+            }
+        }
+
+        pub fn get_content_type(&self) -> &'static str {
+            match self {
+                ExpositionFormat::Text => "text/plain; version=0.0.4; charset=utf-8",
+                ExpositionFormat::OpenMetrics => "application/openmetrics-text; version=1.0.0; charset=utf-8",
+            }
+        }
+    }
+
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    pub enum FamilyKind {
+        Counter,
+        Gauge,
+        Histogram,
+    }
+
+    impl FamilyKind {
+        fn get_name(&self) -> &'static str {
+            match self {
+                FamilyKind::Counter => "counter",
+                FamilyKind::Gauge => "gauge",
+                FamilyKind::Histogram => "histogram",
+            }
+        }
+    }
+
+    // one line of a family, suffix is appended to the family name ("_total", "_bucket", "" ...)
+    pub struct Sample {
+        pub suffix: &'static str,
+        pub labels: Vec<(String, String)>,
+        pub value: f64,
+    }
+
+    // Writes a metric family, its HELP and TYPE lines and then every sample.
+    // name is the family name without any suffix, counters get their "_total" from the samples.
+    pub fn write_family(out: &mut String, format: ExpositionFormat, name: &str, help: &str, kind: FamilyKind, samples: &[Sample]) {
+        // the text format names a counter family after its samples, OpenMetrics after the bare name
+        let family_name = match (format, kind) {
+            (ExpositionFormat::Text, FamilyKind::Counter) => format!("{}_total", name),
+            _ => String::from(name),
+        };
+
+        out.push_str(&format!("# HELP {} {}\n", family_name, escape_help(help)));
+        out.push_str(&format!("# TYPE {} {}\n", family_name, kind.get_name()));
+
+        for sample in samples {
+            out.push_str(name);
+            out.push_str(sample.suffix);
+            if !sample.labels.is_empty() {
+                let labels: Vec<String> = sample
+                    .labels
+                    .iter()
+                    .map(|(label, value)| format!("{}=\"{}\"", label, escape_label_value(value)))
+                    .collect();
+                out.push('{');
+                out.push_str(&labels.join(","));
+                out.push('}');
+            }
+            out.push(' ');
+            out.push_str(&format_value(sample.value));
+            out.push('\n');
+        }
+    }
+
+    // the end of an exposition, OpenMetrics requires a terminating "# EOF"
+    pub fn finish(out: &mut String, format: ExpositionFormat) {
+        if format == ExpositionFormat::OpenMetrics {
+            out.push_str("# EOF\n");
+        }
+    }
+
+    fn format_value(value: f64) -> String {
+        if value.is_nan() {
+            String::from("NaN")
+        } else if value.is_infinite() {
+            String::from(if value > 0.0 { "+Inf" } else { "-Inf" })
+        } else if value.fract() == 0.0 && value.abs() < 1e15 {
+            format!("{}", value as i64)
+        } else {
+            format!("{}", value)
+        }
+    }
+
+    fn escape_label_value(value: &str) -> String {
+        value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+    }
+
+    fn escape_help(help: &str) -> String {
+        help.replace('\\', "\\\\").replace('\n', "\\n")
+    }
+
+    // Tag keys are letters, digits, '_' and '-', label names can't have '-' or start with a digit.
+    // Prefixed so a tag can never collide with the metric_type / target labels.
+    fn get_tag_label_name(tag_key: &str) -> String {
+        let sanitized: String = tag_key
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' })
+            .collect();
+        format!("tag_{}", sanitized)
+    }
+
+    // how many distinct label values the aggregator will keep apart
+    #[derive(Clone, Copy, Debug)]
+    pub struct CardinalityLimits {
+        pub max_targets_per_type: usize,
+        pub max_tag_keys: usize,
+        pub max_values_per_tag: usize,
+        pub max_series: usize,
+    }
+
+    #[derive(PartialEq, Eq, PartialOrd, Ord, Clone)]
+    struct SeriesKey {
+        metric_type: String,
+        target: String,
+        // label name -> value, already sanitized and capped
+        tags: BTreeMap<String, String>,
+    }
+
+    #[derive(Default)]
+    struct SeriesTotals {
+        events: u64,
+        value_sum: f64,
+    }
+
+    #[derive(Default)]
+    struct AggregatorState {
+        series: BTreeMap<SeriesKey, SeriesTotals>,
+        // metric type -> the targets that got their own label
+        targets_seen: HashMap<String, HashSet<String>>,
+        // tag label name -> the tag key that got it, the first one to, of every key that sanitizes to it
+        tag_labels: HashMap<String, String>,
+        // tag label name -> the values that got their own label
+        tag_values_seen: HashMap<String, HashSet<String>>,
+        // events that had at least one label folded into __other__
+        folded_events: u64,
+    }
+
+    pub struct MetricAggregator {
+        limits: CardinalityLimits,
+        state: Mutex<AggregatorState>,
+    }
+
+    impl MetricAggregator {
+        pub fn new(limits: CardinalityLimits) -> MetricAggregator {
+            MetricAggregator {
+                limits,
+                state: Mutex::new(AggregatorState::default()),
+            }
+        }
+
+        pub fn record(&self, metric: &Metric) {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            let state = &mut *state;
+            let metric_type = metric.metric_type.get_name();
+            let mut folded = false;
+
+            let target: String = metric.target.chars().take(MAX_TARGET_LABEL_LENGTH).collect();
+            let targets_seen = state.targets_seen.entry(metric_type.clone()).or_default();
+            let target = if targets_seen.contains(&target) || targets_seen.len() < self.limits.max_targets_per_type {
+                targets_seen.insert(target.clone());
+                target
+            } else {
+                folded = true;
+                String::from(OTHER_LABEL_VALUE)
+            };
+
+            let mut tags: BTreeMap<String, String> = BTreeMap::new();
+            for (tag_key, tag_value) in metric.tags.iter() {
+                let label_name = get_tag_label_name(tag_key);
+                let has_label = match state.tag_labels.get(&label_name) {
+                    Some(owner) => owner == tag_key,
+                    None if label_name != OTHER_TAG_LABEL_NAME && state.tag_labels.len() < self.limits.max_tag_keys => {
+                        state.tag_labels.insert(label_name.clone(), tag_key.clone());
+                        true
+                    }
+                    None => false,
+                };
+                if !has_label {
+                    folded = true;
+                    tags.insert(String::from(OTHER_TAG_LABEL_NAME), String::from(OTHER_LABEL_VALUE));
+                    continue;
+                }
+
+                let values_seen = state.tag_values_seen.entry(label_name.clone()).or_default();
+                let label_value = if values_seen.contains(tag_value) || values_seen.len() < self.limits.max_values_per_tag {
+                    values_seen.insert(tag_value.clone());
+                    tag_value.clone()
+                } else {
+                    folded = true;
+                    String::from(OTHER_LABEL_VALUE)
+                };
+                tags.insert(label_name, label_value);
+            }
+
+            let mut key = SeriesKey { metric_type, target, tags };
+            if !state.series.contains_key(&key) && state.series.len() >= self.limits.max_series {
+                folded = true;
+                key = SeriesKey {
+                    metric_type: key.metric_type,
+                    target: String::from(OTHER_LABEL_VALUE),
+                    tags: BTreeMap::new(),
+                };
+            }
+
+            let totals = state.series.entry(key).or_default();
+            totals.events += 1;
+            totals.value_sum += metric.value.as_f64();
+
+            if folded {
+                state.folded_events += 1;
+            }
+        }
+
+        // the ingested metric families, ready to go out in a scrape
+        pub fn write_families(&self, out: &mut String, format: ExpositionFormat) {
+            let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+
+            let get_labels = |key: &SeriesKey| {
+                let mut labels = vec![
+                    (String::from("metric_type"), key.metric_type.clone()),
+                    (String::from("target"), key.target.clone()),
+                ];
+                labels.extend(key.tags.iter().map(|(name, value)| (name.clone(), value.clone())));
+                labels
+            };
+
+            let events: Vec<Sample> = state
+                .series
+                .iter()
+                .map(|(key, totals)| Sample { suffix: "_total", labels: get_labels(key), value: totals.events as f64 })
+                .collect();
+            write_family(
+                out,
+                format,
+                "couch_gag_metric_events",
+                "Metrics ingested by the hub, by metric type, target and tags.",
+                FamilyKind::Counter,
+                &events,
+            );
+
+            let value_sums: Vec<Sample> = state
+                .series
+                .iter()
+                .map(|(key, totals)| Sample { suffix: "", labels: get_labels(key), value: totals.value_sum })
+                .collect();
+            write_family(
+                out,
+                format,
+                "couch_gag_metric_value_sum",
+                "Sum of the values of ingested metrics (durations in milliseconds), may go down as counters can be corrected.",
+                FamilyKind::Gauge,
+                &value_sums,
+            );
+
+            write_family(
+                out,
+                format,
+                "couch_gag_metric_folded_events",
+                "Ingested metrics with a target, tag key or tag value folded into __other__ by the cardinality caps.",
+                FamilyKind::Counter,
+                &[Sample { suffix: "_total", labels: vec![], value: state.folded_events as f64 }],
+            );
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::metrics::metric::{MetricName, MetricValue};
+
+        const ROOMY: CardinalityLimits = CardinalityLimits {
+            max_targets_per_type: 100,
+            max_tag_keys: 100,
+            max_values_per_tag: 100,
+            max_series: 100,
+        };
+
+        fn get_metric(metric_type: MetricName, target: &str, tags: &[(&str, &str)]) -> Metric {
+            let value = MetricValue::Counter(1);
+            let mut metric = Metric::get_metric(metric_type, String::new(), String::from(target), value);
+            metric.tags = tags.iter().map(|(k, v)| (String::from(*k), String::from(*v))).collect();
+            metric
+        }
+
+        // the couch_gag_metric_events_total samples, without the metric name
+        fn get_event_lines(aggregator: &MetricAggregator) -> Vec<String> {
+            let mut out = String::new();
+            aggregator.write_families(&mut out, ExpositionFormat::Text);
+            out.lines()
+                .filter_map(|line| line.strip_prefix("couch_gag_metric_events_total"))
+                .map(String::from)
+                .collect()
+        }
+
+        fn get_folded_events(aggregator: &MetricAggregator) -> u64 {
+            aggregator.state.lock().unwrap().folded_events
+        }
+
+        #[test]
+        fn folds_targets_past_the_cap_per_type() {
+            let aggregator = MetricAggregator::new(CardinalityLimits { max_targets_per_type: 2, ..ROOMY });
+            for target in ["s1", "s2", "s3", "s1", "s4"].iter() {
+                aggregator.record(&get_metric(MetricName::StoryView, target, &[]));
+            }
+            // the cap is per metric type
+            aggregator.record(&get_metric(MetricName::Share, "s3", &[]));
+
+            assert_eq!(
+                get_event_lines(&aggregator),
+                vec![
+                    "{metric_type=\"Share\",target=\"s3\"} 1",
+                    "{metric_type=\"StoryView\",target=\"__other__\"} 2",
+                    "{metric_type=\"StoryView\",target=\"s1\"} 2",
+                    "{metric_type=\"StoryView\",target=\"s2\"} 1",
+                ]
+            );
+            assert_eq!(get_folded_events(&aggregator), 2);
+        }
+
+        #[test]
+        fn folds_tag_keys_past_the_cap() {
+            let aggregator = MetricAggregator::new(CardinalityLimits { max_tag_keys: 1, ..ROOMY });
+            aggregator.record(&get_metric(MetricName::StoryView, "s1", &[("lang", "en")]));
+            aggregator.record(&get_metric(MetricName::StoryView, "s1", &[("lang", "en"), ("theme", "dark")]));
+            aggregator.record(&get_metric(MetricName::StoryView, "s1", &[("device", "phone"), ("theme", "light")]));
+
+            assert_eq!(
+                get_event_lines(&aggregator),
+                vec![
+                    "{metric_type=\"StoryView\",target=\"s1\",tag___other__=\"__other__\"} 1",
+                    "{metric_type=\"StoryView\",target=\"s1\",tag___other__=\"__other__\",tag_lang=\"en\"} 1",
+                    "{metric_type=\"StoryView\",target=\"s1\",tag_lang=\"en\"} 1",
+                ]
+            );
+            assert_eq!(get_folded_events(&aggregator), 2);
+        }
+
+        #[test]
+        fn folds_tag_keys_whose_label_name_is_taken() {
+            let aggregator = MetricAggregator::new(ROOMY);
+            aggregator.record(&get_metric(MetricName::StoryView, "s1", &[("a-b", "1")]));
+            aggregator.record(&get_metric(MetricName::StoryView, "s1", &[("a_b", "2")]));
+            // a client sending the fold label's own key can't pose as it
+            aggregator.record(&get_metric(MetricName::StoryView, "s1", &[("__other__", "3")]));
+
+            assert_eq!(
+                get_event_lines(&aggregator),
+                vec![
+                    "{metric_type=\"StoryView\",target=\"s1\",tag___other__=\"__other__\"} 2",
+                    "{metric_type=\"StoryView\",target=\"s1\",tag_a_b=\"1\"} 1",
+                ]
+            );
+            assert_eq!(get_folded_events(&aggregator), 2);
+        }
+
+        #[test]
+        fn folds_tag_values_past_the_cap_per_key() {
+            let aggregator = MetricAggregator::new(CardinalityLimits { max_values_per_tag: 1, ..ROOMY });
+            aggregator.record(&get_metric(MetricName::StoryView, "s1", &[("lang", "en")]));
+            aggregator.record(&get_metric(MetricName::StoryView, "s1", &[("lang", "de")]));
+            aggregator.record(&get_metric(MetricName::StoryView, "s1", &[("lang", "en"), ("theme", "dark")]));
+
+            assert_eq!(
+                get_event_lines(&aggregator),
+                vec![
+                    "{metric_type=\"StoryView\",target=\"s1\",tag_lang=\"__other__\"} 1",
+                    "{metric_type=\"StoryView\",target=\"s1\",tag_lang=\"en\"} 1",
+                    "{metric_type=\"StoryView\",target=\"s1\",tag_lang=\"en\",tag_theme=\"dark\"} 1",
+                ]
+            );
+            assert_eq!(get_folded_events(&aggregator), 1);
+        }
+
+        #[test]
+        fn folds_new_series_past_max_series() {
+            let aggregator = MetricAggregator::new(CardinalityLimits { max_series: 2, ..ROOMY });
+            aggregator.record(&get_metric(MetricName::StoryView, "s1", &[]));
+            aggregator.record(&get_metric(MetricName::StoryView, "s2", &[]));
+            aggregator.record(&get_metric(MetricName::StoryView, "s3", &[("lang", "en")]));
+            aggregator.record(&get_metric(MetricName::Share, "s1", &[]));
+            // series that already exist keep counting
+            aggregator.record(&get_metric(MetricName::StoryView, "s1", &[]));
+
+            assert_eq!(
+                get_event_lines(&aggregator),
+                vec![
+                    "{metric_type=\"Share\",target=\"__other__\"} 1",
+                    "{metric_type=\"StoryView\",target=\"__other__\"} 1",
+                    "{metric_type=\"StoryView\",target=\"s1\"} 2",
+                    "{metric_type=\"StoryView\",target=\"s2\"} 1",
+                ]
+            );
+            assert_eq!(get_folded_events(&aggregator), 2);
+        }
+
+        #[test]
+        fn picks_the_format_off_the_accept_header() {
+            let from_accept = ExpositionFormat::from_accept_header;
+            assert_eq!(from_accept(""), ExpositionFormat::Text);
+            assert_eq!(from_accept("text/plain;version=0.0.4;q=0.5,*/*;q=0.1"), ExpositionFormat::Text);
+            assert_eq!(
+                from_accept("application/openmetrics-text;version=1.0.0;q=0.9,text/plain;q=0.5"),
+                ExpositionFormat::OpenMetrics
+            );
+            assert_eq!(from_accept("Application/OpenMetrics-Text"), ExpositionFormat::OpenMetrics);
+            // q=0 means not acceptable
+            assert_eq!(from_accept("application/openmetrics-text; q=0, text/plain"), ExpositionFormat::Text);
+            assert_eq!(from_accept("application/openmetrics-text;q = 0"), ExpositionFormat::Text);
+        }
+    }
+}
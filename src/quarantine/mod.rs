@@ -0,0 +1,164 @@
+pub mod quarantine {
+
+    // Events we refused to record land here instead of vanishing, so a typo'd metric key or a
+    // client sending the wrong value kind shows up at GET /admin/quarantine rather than as a
+    // mysterious gap in the numbers.
+    //
+    // Only the most recent events are held, in memory. It's a debugging aid, not a second metric log.
+
+    use crate::http_request::http_request_base_kit::HttpRequest;
+    use crate::utils::utils::{decode_url_component, get_unix_time_millis};
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    // payloads past this are cut short, a quarantined event should be readable, not complete
+    const MAX_QUARANTINED_PAYLOAD_BYTES: usize = 4 * 1024;
+
+    // beacons and pixels carry their bearer token in the payload, it's never kept
+    const TOKEN_FIELD: &str = "token";
+
+    #[derive(Serialize, Clone, Debug)]
+    pub struct QuarantinedEvent {
+        // unix millis, by the hub's clock
+        pub received_at: u64,
+        pub method: String,
+        pub path: String,
+        // the query string for GET requests, the body otherwise, without any token
+        pub payload: String,
+        pub errors: Vec<(String, String)>,
+        pub remote_addr: Option<String>,
+        pub user_agent: Option<String>,
+    }
+
+    impl QuarantinedEvent {
+        pub fn get_event_off_request(request: &HttpRequest, errors: Vec<(String, String)>) -> QuarantinedEvent {
+            let payload = if request.get_http_method() == "GET" {
+                request.path.split_once('?').map(|(_, query)| String::from(query)).unwrap_or_default()
+            } else {
+                request.get_body()
+            };
+
+            let user_agent = request.get_header_by_key(String::from("User-Agent"));
+
+            QuarantinedEvent {
+                received_at: get_unix_time_millis(),
+                method: request.get_http_method(),
+                path: request.get_path(),
+                payload: truncate_payload(strip_token(payload)),
+                errors,
+                remote_addr: request.remote_addr.clone(),
+                user_agent: if user_agent.is_empty() { None } else { Some(user_agent) },
+            }
+        }
+    }
+
+    // Drops the token field off a JSON object body, or the token pair off a query string or
+    // form body. Anything else, and payloads without a token, are left exactly as they came.
+    fn strip_token(payload: String) -> String {
+        if let Ok(serde_json::Value::Object(mut fields)) = serde_json::from_str::<serde_json::Value>(&payload) {
+            return match fields.remove(TOKEN_FIELD) {
+                Some(_) => serde_json::Value::Object(fields).to_string(),
+                None => payload,
+            };
+        }
+
+        let pairs: Vec<&str> = payload.split('&').collect();
+        let kept: Vec<&str> = pairs
+            .iter()
+            .cloned()
+            .filter(|pair| {
+                let key = pair.split_once('=').map_or(*pair, |(key, _)| key);
+                decode_url_component(key) != TOKEN_FIELD
+            })
+            .collect();
+        if kept.len() == pairs.len() {
+            payload
+        } else {
+            kept.join("&")
+        }
+    }
+
+    fn truncate_payload(mut payload: String) -> String {
+        if payload.len() > MAX_QUARANTINED_PAYLOAD_BYTES {
+            let mut cut = MAX_QUARANTINED_PAYLOAD_BYTES;
+            while !payload.is_char_boundary(cut) {
+                cut -= 1;
+            }
+            payload.truncate(cut);
+        }
+        payload
+    }
+
+    struct QuarantineState {
+        events: VecDeque<QuarantinedEvent>,
+        // every event ever quarantined, including the ones since pushed out
+        total: usize,
+    }
+
+    pub struct QuarantineStore {
+        capacity: usize,
+        state: Mutex<QuarantineState>,
+    }
+
+    impl QuarantineStore {
+        pub fn new(capacity: usize) -> QuarantineStore {
+            QuarantineStore {
+                capacity,
+                state: Mutex::new(QuarantineState {
+                    events: VecDeque::with_capacity(capacity),
+                    total: 0,
+                }),
+            }
+        }
+
+        // holds on to the event, pushing out the oldest one when full
+        pub fn add(&self, event: QuarantinedEvent) {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            if state.events.len() >= self.capacity {
+                state.events.pop_front();
+            }
+            state.events.push_back(event);
+            state.total += 1;
+        }
+
+        // up to limit of the held events, newest first, and the all-time total
+        pub fn get_recent(&self, limit: usize) -> (Vec<QuarantinedEvent>, usize) {
+            let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            let events = state.events.iter().rev().take(limit).cloned().collect();
+            (events, state.total)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn strips_the_token_off_json_bodies() {
+            let payload = String::from(r#"{"metric_type":"story-view","target":"s1","token":"1700000000.website.abcd"}"#);
+            assert_eq!(strip_token(payload), r#"{"metric_type":"story-view","target":"s1"}"#);
+
+            let payload = String::from(r#"{"metric_type": "story-view"}"#);
+            assert_eq!(strip_token(payload.clone()), payload);
+        }
+
+        #[test]
+        fn strips_the_token_off_query_strings_and_forms() {
+            let payload = String::from("metric_type=story-view&token=1700000000.website.abcd&target=s%201");
+            assert_eq!(strip_token(payload), "metric_type=story-view&target=s%201");
+
+            // however the key is escaped, and however often it's sent
+            assert_eq!(strip_token(String::from("t%6Fken=a&target=s1&token")), "target=s1");
+
+            let payload = String::from("metric_type=story-view&tokens=2&&target=s1");
+            assert_eq!(strip_token(payload.clone()), payload);
+        }
+
+        #[test]
+        fn leaves_other_payloads_alone() {
+            for payload in ["", "not json, not a form", "[1, 2]", r#"{"token": "#].iter() {
+                assert_eq!(strip_token(String::from(*payload)), *payload);
+            }
+        }
+    }
+}
@@ -0,0 +1,500 @@
+pub mod metric_query {
+
+    // GET /query, aggregates the recorded metrics into a time series.
+    //
+    //   metric=<wire key>                    only this metric type
+    //   target=<target>                      only this target
+    //   tag.<key>=<value>                    only metrics carrying this tag, may be repeated
+    //   from=<unix ms>, to=<unix ms>         event time range, from inclusive, to exclusive
+    //   aggregation=count|sum|avg|min|max    what to do with the values, count when left out
+    //   group_by=target,tag.<key>            one series per distinct combination, a single series when left out
+    //   interval=<duration>                  bucket width ("30s", "5m", "1h", "1d"), one point per series when left out
+    //   limit=<n>                            the most series returned, busiest first
+    //
+    // Points only exist for buckets something landed in, an empty bucket is simply left out.
+
+    use crate::metrics::metric::{Metric, MetricValue};
+    use crate::registry::metric_registry::MetricRegistry;
+    use crate::router::router::RouteRequest;
+    use crate::storage::metric_store::MetricFilter;
+    use std::collections::BTreeMap;
+
+    const DEFAULT_SERIES_LIMIT: usize = 100;
+    const MAX_SERIES_LIMIT: usize = 1000;
+
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    pub enum Aggregation {
+        Count,
+        Sum,
+        Avg,
+        Min,
+        Max,
+    }
+
+    impl Aggregation {
+        pub fn from_name(name: &str) -> Option<Aggregation> {
+            match name {
+                "count" => Some(Aggregation::Count),
+                "sum" => Some(Aggregation::Sum),
+                "avg" => Some(Aggregation::Avg),
+                "min" => Some(Aggregation::Min),
+                "max" => Some(Aggregation::Max),
+                _ => None,
+            }
+        }
+
+        pub fn get_name(&self) -> &'static str {
+            match self {
+                Aggregation::Count => "count",
+                Aggregation::Sum => "sum",
+                Aggregation::Avg => "avg",
+                Aggregation::Min => "min",
+                Aggregation::Max => "max",
+            }
+        }
+    }
+
+    // what a series can be split by
+    #[derive(Clone, PartialEq, Debug)]
+    pub enum GroupKey {
+        Target,
+        Tag(String),
+    }
+
+    impl GroupKey {
+        // "target" or "tag.<key>", the same names group_by takes
+        pub fn get_label(&self) -> String {
+            match self {
+                GroupKey::Target => String::from("target"),
+                GroupKey::Tag(key) => format!("tag.{}", key),
+            }
+        }
+
+        // None when the metric doesn't have the tag, those get a series of their own
+        fn get_value(&self, metric: &Metric) -> Option<String> {
+            match self {
+                GroupKey::Target => Some(metric.target.clone()),
+                GroupKey::Tag(key) => metric.tags.get(key).cloned(),
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct MetricQuery {
+        // everything that narrows down which metrics are read, time range included
+        pub filter: MetricFilter,
+        pub aggregation: Aggregation,
+        pub group_by: Vec<GroupKey>,
+        pub interval_ms: Option<u64>,
+        pub limit: usize,
+    }
+
+    // a series' values for each group_by key, in order
+    type GroupValues = Vec<Option<String>>;
+    // bucket start -> totals
+    type Points = BTreeMap<u64, Accumulator>;
+
+    // the running totals for one point of one series
+    struct Accumulator {
+        count: u64,
+        sum: f64,
+        min: f64,
+        max: f64,
+        first_at: u64,
+    }
+
+    impl Accumulator {
+        fn new(first_at: u64) -> Accumulator {
+            Accumulator {
+                count: 0,
+                sum: 0.0,
+                min: f64::INFINITY,
+                max: f64::NEG_INFINITY,
+                first_at,
+            }
+        }
+
+        fn add(&mut self, value: f64, event_time: u64) {
+            self.count += 1;
+            self.sum += value;
+            self.min = self.min.min(value);
+            self.max = self.max.max(value);
+            self.first_at = self.first_at.min(event_time);
+        }
+
+        fn get_value(&self, aggregation: Aggregation) -> serde_json::Value {
+            match aggregation {
+                Aggregation::Count => serde_json::json!(self.count),
+                Aggregation::Sum => serde_json::json!(self.sum),
+                Aggregation::Avg => serde_json::json!(self.sum / self.count as f64),
+                Aggregation::Min => serde_json::json!(self.min),
+                Aggregation::Max => serde_json::json!(self.max),
+            }
+        }
+    }
+
+    impl MetricQuery {
+        // Reads a query off the request's params. Every bad param is reported,
+        // as ("ValidationError", "<param>: <message>").
+        pub fn from_route(route: &RouteRequest, registry: &MetricRegistry) -> Result<MetricQuery, Vec<(String, String)>> {
+            let mut errors: Vec<(String, String)> = Vec::new();
+            let mut error = |param: &str, message: String| {
+                errors.push((String::from("ValidationError"), format!("{}: {}", param, message)));
+            };
+
+            let mut filter = MetricFilter::default();
+
+            if let Some(wire_key) = route.get_query_param("metric") {
+                match registry.get_metric_type_from_wire_key(wire_key) {
+                    Some(metric_type) => filter.metric_type = Some(metric_type),
+                    None => error("metric", format!("'{}' is not a known metric type", wire_key)),
+                }
+            }
+            filter.target = route.get_query_param("target").map(String::from);
+
+            let mut get_time = |param: &str| match route.get_query_param(param) {
+                None => None,
+                Some(raw) => match raw.parse::<u64>() {
+                    Ok(time) => Some(time),
+                    Err(_) => {
+                        error(param, format!("'{}' is not a unix timestamp in milliseconds", raw));
+                        None
+                    }
+                },
+            };
+            filter.since = get_time("from");
+            filter.until = get_time("to");
+            if let (Some(from), Some(to)) = (filter.since, filter.until) {
+                if from >= to {
+                    error("to", String::from("must be after from"));
+                }
+            }
+
+            let aggregation = match route.get_query_param("aggregation") {
+                None => Aggregation::Count,
+                Some(name) => Aggregation::from_name(name).unwrap_or_else(|| {
+                    error("aggregation", format!("'{}' is not one of count, sum, avg, min, max", name));
+                    Aggregation::Count
+                }),
+            };
+
+            let mut group_by: Vec<GroupKey> = Vec::new();
+            for name in route.get_query_param("group_by").unwrap_or("").split(',').map(|name| name.trim()) {
+                let group_key = match name {
+                    "" => continue,
+                    "target" => GroupKey::Target,
+                    _ => match name.strip_prefix("tag.") {
+                        Some(key) if !key.is_empty() => GroupKey::Tag(String::from(key)),
+                        _ => {
+                            error("group_by", format!("'{}' is not target or tag.<key>", name));
+                            continue;
+                        }
+                    },
+                };
+                if !group_by.contains(&group_key) {
+                    group_by.push(group_key);
+                }
+            }
+
+            let interval_ms = match route.get_query_param("interval") {
+                None => None,
+                Some(raw) => match MetricValue::get_value_off_str(raw, Some("duration")) {
+                    Ok(MetricValue::Duration(millis)) if millis > 0 => Some(millis),
+                    Ok(_) => {
+                        error("interval", String::from("must be longer than 0ms"));
+                        None
+                    }
+                    Err(message) => {
+                        error("interval", message);
+                        None
+                    }
+                },
+            };
+
+            let limit = route
+                .get_query_param("limit")
+                .and_then(|value| value.parse::<usize>().ok())
+                .unwrap_or(DEFAULT_SERIES_LIMIT)
+                .min(MAX_SERIES_LIMIT);
+
+            match Metric::get_tags_off_query_params(&route.url) {
+                Ok(tags) => filter.tags = tags,
+                Err(tag_errors) => errors.extend(tag_errors),
+            }
+
+            if !errors.is_empty() {
+                return Err(errors);
+            }
+
+            Ok(MetricQuery {
+                filter,
+                aggregation,
+                group_by,
+                interval_ms,
+                limit,
+            })
+        }
+
+        // Aggregates metrics the filter already let through into the response body.
+        pub fn run(&self, metrics: &[Metric]) -> serde_json::Value {
+            let mut series: BTreeMap<GroupValues, Points> = BTreeMap::new();
+
+            for metric in metrics {
+                let group: GroupValues = self.group_by.iter().map(|key| key.get_value(metric)).collect();
+                let event_time = metric.get_event_time();
+                let bucket_start = match self.interval_ms {
+                    Some(interval_ms) => event_time - event_time % interval_ms,
+                    None => 0,
+                };
+
+                series
+                    .entry(group)
+                    .or_default()
+                    .entry(bucket_start)
+                    .or_insert_with(|| Accumulator::new(event_time))
+                    .add(metric.value.as_f64(), event_time);
+            }
+
+            // busiest series first, so the limit cuts off the long tail
+            let mut series: Vec<(GroupValues, Points, u64)> = series
+                .into_iter()
+                .map(|(group, points)| {
+                    let total: u64 = points.values().map(|point| point.count).sum();
+                    (group, points, total)
+                })
+                .collect();
+            series.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+            let total_series = series.len();
+
+            let group_labels: Vec<String> = self.group_by.iter().map(|key| key.get_label()).collect();
+            let series_json: Vec<serde_json::Value> = series
+                .into_iter()
+                .take(self.limit)
+                .map(|(group, points, total)| {
+                    let group_json: serde_json::Map<String, serde_json::Value> =
+                        group_labels.iter().cloned().zip(group.into_iter().map(serde_json::Value::from)).collect();
+                    let points_json: Vec<serde_json::Value> = points
+                        .iter()
+                        .map(|(bucket_start, point)| {
+                            // without an interval the one point starts at the series' first metric
+                            let start = if self.interval_ms.is_some() { *bucket_start } else { point.first_at };
+                            serde_json::json!({
+                                "start": start,
+                                "count": point.count,
+                                "value": point.get_value(self.aggregation),
+                            })
+                        })
+                        .collect();
+
+                    serde_json::json!({
+                        "group": group_json,
+                        "count": total,
+                        "points": points_json,
+                    })
+                })
+                .collect();
+
+            serde_json::json!({
+                "aggregation": self.aggregation.get_name(),
+                "group_by": group_labels,
+                "interval_ms": self.interval_ms,
+                "from": self.filter.since,
+                "to": self.filter.until,
+                "matched": metrics.len(),
+                "total_series": total_series,
+                "returned": series_json.len(),
+                "series": series_json,
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::http_request::http_request_base_kit::HttpRequest;
+        use crate::metrics::metric::MetricName;
+        use crate::url::url::ReqUrl;
+        use std::collections::HashMap;
+
+        fn get_request() -> HttpRequest {
+            HttpRequest {
+                method: String::from("GET"),
+                headers: HashMap::new(),
+                path: String::from("/query"),
+                version: String::from("HTTP/1.1"),
+                data: String::new(),
+                remote_addr: None,
+            }
+        }
+
+        fn parse_query(params: &[(&str, &str)]) -> Result<MetricQuery, Vec<(String, String)>> {
+            let request = get_request();
+            let route = RouteRequest {
+                request: &request,
+                url: ReqUrl {
+                    path: String::from("/query"),
+                    query_parameters: params.iter().map(|(k, v)| (String::from(*k), String::from(*v))).collect(),
+                },
+                params: HashMap::new(),
+                api_key: None,
+            };
+            MetricQuery::from_route(&route, &MetricRegistry::with_built_ins())
+        }
+
+        fn get_metric(target: &str, value: i64, at: u64, tags: &[(&str, &str)]) -> Metric {
+            let value = MetricValue::Counter(value);
+            let mut metric = Metric::get_metric(MetricName::StoryView, String::new(), String::from(target), value);
+            metric.received_at = at;
+            metric.tags = tags.iter().map(|(k, v)| (String::from(*k), String::from(*v))).collect();
+            metric
+        }
+
+        #[test]
+        fn reads_every_param() {
+            let query = parse_query(&[
+                ("metric", "story-view"),
+                ("target", "s1"),
+                ("from", "1000"),
+                ("to", "2000"),
+                ("aggregation", "sum"),
+                ("group_by", "target, tag.lang,target"),
+                ("interval", "1m"),
+                ("limit", "5000"),
+                ("tag.lang", "en"),
+            ])
+            .unwrap();
+
+            assert!(matches!(query.filter.metric_type, Some(MetricName::StoryView)));
+            assert_eq!(query.filter.target.as_deref(), Some("s1"));
+            assert_eq!((query.filter.since, query.filter.until), (Some(1000), Some(2000)));
+            assert_eq!(query.filter.tags.get("lang").map(String::as_str), Some("en"));
+            assert_eq!(query.aggregation, Aggregation::Sum);
+            assert_eq!(query.group_by, vec![GroupKey::Target, GroupKey::Tag(String::from("lang"))]);
+            assert_eq!(query.interval_ms, Some(60_000));
+            assert_eq!(query.limit, MAX_SERIES_LIMIT);
+        }
+
+        #[test]
+        fn reports_every_bad_param() {
+            let errors = parse_query(&[
+                ("metric", "nope"),
+                ("from", "2000"),
+                ("to", "1000"),
+                ("aggregation", "median"),
+                ("group_by", "tag.,user"),
+                ("interval", "0s"),
+            ])
+            .unwrap_err();
+
+            let messages: Vec<&str> = errors.iter().map(|(_, message)| message.as_str()).collect();
+            assert!(errors.iter().all(|(kind, _)| kind == "ValidationError"));
+            assert_eq!(
+                messages,
+                vec![
+                    "metric: 'nope' is not a known metric type",
+                    "to: must be after from",
+                    "aggregation: 'median' is not one of count, sum, avg, min, max",
+                    "group_by: 'tag.' is not target or tag.<key>",
+                    "group_by: 'user' is not target or tag.<key>",
+                    "interval: must be longer than 0ms",
+                ]
+            );
+
+            let errors = parse_query(&[("from", "yesterday"), ("interval", "soon")]).unwrap_err();
+            assert_eq!(errors.len(), 2);
+            assert!(errors[0].1.starts_with("from: 'yesterday' is not a unix timestamp"));
+            assert!(errors[1].1.starts_with("interval: "));
+        }
+
+        #[test]
+        fn groups_by_target_and_tag() {
+            let query = parse_query(&[("group_by", "target,tag.lang"), ("aggregation", "sum")]).unwrap();
+            let metrics = vec![
+                get_metric("s1", 1, 10, &[("lang", "en")]),
+                get_metric("s1", 2, 20, &[("lang", "en")]),
+                get_metric("s1", 4, 30, &[("lang", "de")]),
+                get_metric("s2", 8, 40, &[]),
+            ];
+            let result = query.run(&metrics);
+
+            assert_eq!(result["matched"], 4);
+            assert_eq!(result["total_series"], 3);
+            assert_eq!(result["group_by"], serde_json::json!(["target", "tag.lang"]));
+            // busiest first, ties broken by group
+            let series = result["series"].as_array().unwrap();
+            assert_eq!(series[0]["group"], serde_json::json!({"target": "s1", "tag.lang": "en"}));
+            assert_eq!(series[0]["count"], 2);
+            assert_eq!(series[0]["points"], serde_json::json!([{"start": 10, "count": 2, "value": 3.0}]));
+            assert_eq!(series[1]["group"], serde_json::json!({"target": "s1", "tag.lang": "de"}));
+            // a missing tag is a series of its own, with a null value
+            assert_eq!(series[2]["group"], serde_json::json!({"target": "s2", "tag.lang": null}));
+            assert_eq!(series[2]["points"], serde_json::json!([{"start": 40, "count": 1, "value": 8.0}]));
+        }
+
+        #[test]
+        fn buckets_by_interval() {
+            let query = parse_query(&[("interval", "1s"), ("aggregation", "max")]).unwrap();
+            let mut occurred = get_metric("s1", 9, 5_000, &[]);
+            // the event time is when it occurred, not when it was received
+            occurred.occurred_at = Some(1_500);
+            let metrics = vec![
+                get_metric("s1", 3, 1_200, &[]),
+                get_metric("s1", 5, 1_999, &[]),
+                occurred,
+                get_metric("s1", 7, 3_000, &[]),
+            ];
+            let result = query.run(&metrics);
+
+            assert_eq!(result["interval_ms"], 1000);
+            assert_eq!(result["total_series"], 1);
+            assert_eq!(
+                result["series"][0]["points"],
+                serde_json::json!([
+                    {"start": 1000, "count": 3, "value": 9.0},
+                    {"start": 3000, "count": 1, "value": 7.0},
+                ])
+            );
+        }
+
+        #[test]
+        fn aggregates_values() {
+            let metrics = vec![
+                get_metric("s1", 2, 100, &[]),
+                get_metric("s1", 6, 300, &[]),
+                get_metric("s1", 1, 200, &[]),
+            ];
+            let value_for = |aggregation: &str| {
+                let result = parse_query(&[("aggregation", aggregation)]).unwrap().run(&metrics);
+                // without an interval the single point starts at the earliest metric
+                assert_eq!(result["series"][0]["points"][0]["start"], 100);
+                result["series"][0]["points"][0]["value"].clone()
+            };
+
+            assert_eq!(value_for("count"), 3);
+            assert_eq!(value_for("sum"), 9.0);
+            assert_eq!(value_for("avg"), 3.0);
+            assert_eq!(value_for("min"), 1.0);
+            assert_eq!(value_for("max"), 6.0);
+        }
+
+        #[test]
+        fn limits_to_the_busiest_series() {
+            let query = parse_query(&[("group_by", "target"), ("limit", "2")]).unwrap();
+            let metrics = vec![
+                get_metric("quiet", 1, 10, &[]),
+                get_metric("busy", 1, 20, &[]),
+                get_metric("busy", 1, 30, &[]),
+                get_metric("busy", 1, 40, &[]),
+                get_metric("middle", 1, 50, &[]),
+                get_metric("middle", 1, 60, &[]),
+            ];
+            let result = query.run(&metrics);
+
+            assert_eq!(result["total_series"], 3);
+            assert_eq!(result["returned"], 2);
+            let series = result["series"].as_array().unwrap();
+            let targets: Vec<&serde_json::Value> = series.iter().map(|series| &series["group"]["target"]).collect();
+            assert_eq!(targets, vec!["busy", "middle"]);
+        }
+    }
+}
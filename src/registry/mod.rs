@@ -0,0 +1,223 @@
+pub mod metric_registry {
+
+    // Which metric types the hub accepts, and what each one must look like.
+    //
+    // The six types the hub has always known about are built in. More are described in a JSON
+    // registry file loaded at startup, so adding an event like "scroll-depth" is a config change:
+    //
+    // {
+    //   "metric_types": [
+    //     {
+    //       "name": "ScrollDepth",          how it's recorded (metric_type in the log)
+    //       "wire_key": "scroll-depth",      what clients send (?metric=scroll-depth)
+    //       "value_kind": "gauge",           counter | gauge | duration, leave out to allow any
+    //       "required_tags": ["page"],       tags every metric of this type must carry
+    //       "allowed_targets": []            leave empty to allow any target
+    //     }
+    //   ]
+    // }
+
+    use crate::metrics::metric::{Metric, MetricName};
+    use crate::utils::utils::file_reader;
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    const VALUE_KINDS: [&str; 3] = ["counter", "gauge", "duration"];
+
+    #[derive(Deserialize, Serialize, Clone, Debug)]
+    #[serde(deny_unknown_fields)]
+    pub struct MetricTypeDefinition {
+        pub name: String,
+        pub wire_key: String,
+        #[serde(default)]
+        pub value_kind: Option<String>,
+        #[serde(default)]
+        pub required_tags: Vec<String>,
+        #[serde(default)]
+        pub allowed_targets: Vec<String>,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct RegistryFile {
+        metric_types: Vec<MetricTypeDefinition>,
+    }
+
+    pub struct MetricRegistry {
+        // Strict: a metric key we don't know, or none at all, is rejected.
+        // Lenient (how the hub always behaved): unknown keys are recorded as Error, missing ones as Base.
+        strict_types: bool,
+        definitions: Vec<MetricTypeDefinition>,
+        // wire key -> index into definitions
+        by_wire_key: HashMap<String, usize>,
+        // recorded name -> index into definitions
+        by_name: HashMap<String, usize>,
+    }
+
+    impl MetricRegistry {
+        pub fn with_built_ins() -> MetricRegistry {
+            let built_in = |name: MetricName, wire_key: &str| MetricTypeDefinition {
+                name: name.get_name(),
+                wire_key: String::from(wire_key),
+                value_kind: None,
+                required_tags: vec![],
+                allowed_targets: vec![],
+            };
+
+            let mut registry = MetricRegistry {
+                strict_types: false,
+                definitions: vec![],
+                by_wire_key: HashMap::new(),
+                by_name: HashMap::new(),
+            };
+            let built_ins = vec![
+                built_in(MetricName::StoryView, "story-view"),
+                built_in(MetricName::PageView, "page-view"),
+                built_in(MetricName::Share, "share"),
+                built_in(MetricName::ButtonClick, "button-click"),
+                built_in(MetricName::Base, "base"),
+                built_in(MetricName::Error, "error"),
+            ];
+            for definition in built_ins {
+                // the built ins are known good, registering them can't collide
+                let _ = registry.register(definition);
+            }
+            registry
+        }
+
+        // The built ins plus everything in the registry file at path.
+        // A missing file just means no extra types; a file that's there but wrong is an error,
+        // we'd rather refuse to start than silently drop an event type.
+        pub fn load(path: &Path, strict_types: bool) -> Result<MetricRegistry, String> {
+            let mut registry = MetricRegistry::with_built_ins();
+            registry.strict_types = strict_types;
+
+            if !path.exists() {
+                return Ok(registry);
+            }
+
+            let contents = file_reader(&path.to_string_lossy())
+                .map_err(|e| format!("could not read metric registry {} ({})", path.display(), e))?;
+            let registry_file: RegistryFile = serde_json::from_str(&contents)
+                .map_err(|e| format!("metric registry {} is not valid ({})", path.display(), e))?;
+
+            for definition in registry_file.metric_types {
+                registry
+                    .register(definition)
+                    .map_err(|e| format!("metric registry {}: {}", path.display(), e))?;
+            }
+
+            Ok(registry)
+        }
+
+        fn register(&mut self, definition: MetricTypeDefinition) -> Result<(), String> {
+            let is_valid_identifier = |s: &str| {
+                !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+            };
+
+            if !is_valid_identifier(&definition.name) {
+                return Err(format!("'{}' is not a valid metric type name", definition.name));
+            }
+            if !is_valid_identifier(&definition.wire_key) {
+                return Err(format!("'{}' is not a valid wire key", definition.wire_key));
+            }
+            if let Some(value_kind) = &definition.value_kind {
+                if !VALUE_KINDS.contains(&value_kind.as_str()) {
+                    return Err(format!(
+                        "{}: '{}' is not a value kind, expected one of {}",
+                        definition.name,
+                        value_kind,
+                        VALUE_KINDS.join(", ")
+                    ));
+                }
+            }
+            if self.by_name.contains_key(&definition.name) {
+                return Err(format!("metric type '{}' is defined more than once", definition.name));
+            }
+            if self.by_wire_key.contains_key(&definition.wire_key) {
+                return Err(format!("wire key '{}' is defined more than once", definition.wire_key));
+            }
+
+            let index = self.definitions.len();
+            self.by_name.insert(definition.name.clone(), index);
+            self.by_wire_key.insert(definition.wire_key.clone(), index);
+            self.definitions.push(definition);
+            Ok(())
+        }
+
+        pub fn is_strict(&self) -> bool {
+            self.strict_types
+        }
+
+        pub fn get_definitions(&self) -> &[MetricTypeDefinition] {
+            &self.definitions
+        }
+
+        // maps the key clients send ("story-view") to its MetricName
+        pub fn get_metric_type_from_wire_key(&self, wire_key: &str) -> Option<MetricName> {
+            self.by_wire_key
+                .get(wire_key)
+                .map(|index| MetricName::from_name(&self.definitions[*index].name))
+        }
+
+        pub fn get_definition(&self, metric_type: &MetricName) -> Option<&MetricTypeDefinition> {
+            self.by_name
+                .get(&metric_type.get_name())
+                .map(|index| &self.definitions[*index])
+        }
+
+        // the value kind the metric's type is registered with, None when any kind goes
+        pub fn get_value_kind(&self, metric_type: &MetricName) -> Option<&str> {
+            self.get_definition(metric_type)
+                .and_then(|definition| definition.value_kind.as_deref())
+        }
+
+        // (field, message) for every way a metric breaks its type's definition, empty when it's fine
+        pub fn validate(&self, metric: &Metric) -> Vec<(String, String)> {
+            let mut problems: Vec<(String, String)> = Vec::new();
+
+            let definition = match self.get_definition(&metric.metric_type) {
+                Some(definition) => definition,
+                None => {
+                    problems.push((
+                        String::from("metric_type"),
+                        format!("'{}' is not a registered metric type", metric.metric_type.get_name()),
+                    ));
+                    return problems;
+                }
+            };
+
+            if let Some(value_kind) = &definition.value_kind {
+                if metric.value.get_kind() != value_kind {
+                    problems.push((
+                        String::from("value"),
+                        format!(
+                            "{} metrics take a {} value, got a {}",
+                            definition.wire_key,
+                            value_kind,
+                            metric.value.get_kind()
+                        ),
+                    ));
+                }
+            }
+
+            for required_tag in definition.required_tags.iter() {
+                if !metric.tags.contains_key(required_tag) {
+                    problems.push((
+                        format!("tags.{}", required_tag),
+                        format!("is required for {} metrics", definition.wire_key),
+                    ));
+                }
+            }
+
+            if !definition.allowed_targets.is_empty() && !definition.allowed_targets.contains(&metric.target) {
+                problems.push((
+                    String::from("target"),
+                    format!("'{}' is not an allowed target for {} metrics", metric.target, definition.wire_key),
+                ));
+            }
+
+            problems
+        }
+    }
+}
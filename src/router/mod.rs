@@ -0,0 +1,250 @@
+pub mod router {
+
+    // Maps a method and path to the handler that owns the response.
+    //
+    // Patterns are split on '/', a segment starting with ':' matches any single segment and is
+    // handed to the handler by name, so "/stories/:id/metrics" matches "/stories/the-raven/metrics"
+    // with id = "the-raven". A trailing slash makes no difference.
+
+    use crate::api_keys::api_keys::{ApiKey, Scope};
+    use crate::http_request::http_request_base_kit::HttpRequest;
+    use crate::http_response::http_response::HttpResponse;
+    use crate::hub_error::hub_error::HubError;
+    use crate::url::url::ReqUrl;
+    use crate::utils::utils::decode_url_component;
+    use std::collections::HashMap;
+
+    // who may call a route
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    pub enum Access {
+        // callers that can't send headers (beacons, pixels), they bring their own proof if any
+        Public,
+        // our own services, with any x-ulysses-key the hub knows
+        AnyKey,
+        // our own services, with an x-ulysses-key that has this scope
+        Scoped(Scope),
+    }
+
+    // everything a handler gets to look at besides the shared context
+    pub struct RouteRequest<'a> {
+        pub request: &'a HttpRequest,
+        pub url: ReqUrl,
+        // the values of the :name segments in the route's pattern
+        pub params: HashMap<String, String>,
+        // the key the caller authenticated with, None on Public routes
+        pub api_key: Option<&'a ApiKey>,
+    }
+
+    impl<'a> RouteRequest<'a> {
+        pub fn get_param(&self, name: &str) -> Option<&str> {
+            self.params.get(name).map(|value| value.as_str())
+        }
+
+        pub fn get_query_param(&self, name: &str) -> Option<&str> {
+            self.url
+                .query_parameters
+                .iter()
+                .rev()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+        }
+
+        pub fn get_key_id(&self) -> Option<String> {
+            self.api_key.map(|key| key.id.clone())
+        }
+    }
+
+    pub type Handler<C> = fn(&C, &RouteRequest) -> Result<HttpResponse, HubError>;
+
+    enum Segment {
+        Literal(String),
+        Param(String),
+    }
+
+    pub struct Route<C> {
+        pub method: &'static str,
+        pub pattern: &'static str,
+        pub access: Access,
+        pub handler: Handler<C>,
+        segments: Vec<Segment>,
+    }
+
+    pub enum RouteMatch<'r, C> {
+        Found { route: &'r Route<C>, params: HashMap<String, String> },
+        // the path exists, just not for this method, these are the ones it does take
+        MethodNotAllowed(Vec<String>),
+        NotFound,
+    }
+
+    pub struct Router<C> {
+        routes: Vec<Route<C>>,
+    }
+
+    impl<C> Default for Router<C> {
+        fn default() -> Router<C> {
+            Router::new()
+        }
+    }
+
+    impl<C> Router<C> {
+        pub fn new() -> Router<C> {
+            Router { routes: Vec::new() }
+        }
+
+        pub fn add(&mut self, method: &'static str, pattern: &'static str, access: Access, handler: Handler<C>) {
+            let segments = split_path(pattern)
+                .into_iter()
+                .map(|segment| match segment.strip_prefix(':') {
+                    Some(name) => Segment::Param(String::from(name)),
+                    None => Segment::Literal(String::from(segment)),
+                })
+                .collect();
+
+            self.routes.push(Route { method, pattern, access, handler, segments });
+        }
+
+        pub fn get_routes(&self) -> &[Route<C>] {
+            &self.routes
+        }
+
+        pub fn find(&self, method: &str, path: &str) -> RouteMatch<'_, C> {
+            let path_segments = split_path(path);
+            let mut allowed: Vec<String> = Vec::new();
+
+            for route in self.routes.iter() {
+                let params = match match_segments(&route.segments, &path_segments) {
+                    Some(params) => params,
+                    None => continue,
+                };
+
+                if route.method == method {
+                    return RouteMatch::Found { route, params };
+                }
+                allowed.push(String::from(route.method));
+            }
+
+            if allowed.is_empty() {
+                return RouteMatch::NotFound;
+            }
+
+            // preflights are answered for every path we serve
+            allowed.push(String::from("OPTIONS"));
+            allowed.sort();
+            allowed.dedup();
+            RouteMatch::MethodNotAllowed(allowed)
+        }
+
+        // the pattern of the first route serving this path, whatever its method
+        pub fn get_pattern(&self, path: &str) -> Option<&'static str> {
+            let path_segments = split_path(path);
+            self.routes
+                .iter()
+                .find(|route| match_segments(&route.segments, &path_segments).is_some())
+                .map(|route| route.pattern)
+        }
+
+        // every method a path can be called with, empty when nothing is served there
+        pub fn get_allowed_methods(&self, path: &str) -> Vec<String> {
+            match self.find("OPTIONS", path) {
+                RouteMatch::MethodNotAllowed(allowed) => allowed,
+                _ => vec![],
+            }
+        }
+    }
+
+    fn split_path(path: &str) -> Vec<&str> {
+        path.split('/').filter(|segment| !segment.is_empty()).collect()
+    }
+
+    fn match_segments(pattern: &[Segment], path: &[&str]) -> Option<HashMap<String, String>> {
+        if pattern.len() != path.len() {
+            return None;
+        }
+
+        let mut params: HashMap<String, String> = HashMap::new();
+        for (segment, value) in pattern.iter().zip(path.iter()) {
+            match segment {
+                Segment::Literal(literal) if literal == value => {}
+                Segment::Literal(_) => return None,
+                Segment::Param(name) => {
+                    params.insert(name.clone(), decode_url_component(value));
+                }
+            }
+        }
+        Some(params)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        // only ever looked up, never called
+        fn handler(_: &(), _: &RouteRequest) -> Result<HttpResponse, HubError> {
+            unreachable!()
+        }
+
+        fn get_router() -> Router<()> {
+            let mut router = Router::new();
+            router.add("GET", "/stories/latest/metrics", Access::Public, handler);
+            router.add("GET", "/stories/:id/metrics", Access::Scoped(Scope::Query), handler);
+            router.add("POST", "/stories/:id/metrics", Access::Scoped(Scope::Ingest), handler);
+            router.add("GET", "/keys/:id", Access::Scoped(Scope::Admin), handler);
+            router.add("DELETE", "/keys/:id", Access::Scoped(Scope::Admin), handler);
+            router
+        }
+
+        // the matched route's pattern and params, None when nothing matched
+        fn find(router: &Router<()>, method: &str, path: &str) -> Option<(&'static str, HashMap<String, String>)> {
+            match router.find(method, path) {
+                RouteMatch::Found { route, params } => Some((route.pattern, params)),
+                _ => None,
+            }
+        }
+
+        #[test]
+        fn hands_params_to_the_handler() {
+            let router = get_router();
+            let (pattern, params) = find(&router, "POST", "/stories/the-raven/metrics").unwrap();
+            assert_eq!(pattern, "/stories/:id/metrics");
+            assert_eq!(params.get("id").map(String::as_str), Some("the-raven"));
+
+            // url-decoded, and a trailing slash makes no difference
+            let (_, params) = find(&router, "DELETE", "/keys/web%20site/").unwrap();
+            assert_eq!(params.get("id").map(String::as_str), Some("web site"));
+
+            // a param is exactly one segment
+            assert!(find(&router, "GET", "/keys").is_none());
+            assert!(find(&router, "GET", "/keys/a/b").is_none());
+            assert!(find(&router, "GET", "/stories//metrics").is_none());
+        }
+
+        #[test]
+        fn the_first_route_added_wins() {
+            let router = get_router();
+            let (pattern, params) = find(&router, "GET", "/stories/latest/metrics").unwrap();
+            assert_eq!(pattern, "/stories/latest/metrics");
+            assert!(params.is_empty());
+            assert_eq!(router.get_pattern("/stories/latest/metrics"), Some("/stories/latest/metrics"));
+
+            // the literal only shadows the param for its own method
+            let (pattern, params) = find(&router, "POST", "/stories/latest/metrics").unwrap();
+            assert_eq!(pattern, "/stories/:id/metrics");
+            assert_eq!(params.get("id").map(String::as_str), Some("latest"));
+        }
+
+        #[test]
+        fn lists_the_allowed_methods_for_a_wrong_one() {
+            let router = get_router();
+            match router.find("PUT", "/keys/grafana") {
+                RouteMatch::MethodNotAllowed(allowed) => assert_eq!(allowed, vec!["DELETE", "GET", "OPTIONS"]),
+                _ => panic!("expected a 405"),
+            }
+            // overlapping routes all count, once each
+            assert_eq!(router.get_allowed_methods("/stories/latest/metrics"), vec!["GET", "OPTIONS", "POST"]);
+
+            assert!(matches!(router.find("GET", "/nope"), RouteMatch::NotFound));
+            assert!(router.get_allowed_methods("/nope").is_empty());
+            assert_eq!(router.get_pattern("/nope"), None);
+        }
+    }
+}
@@ -0,0 +1,218 @@
+pub mod metric_store {
+
+    use crate::metrics::metric::{Metric, MetricName};
+    use std::collections::{BTreeMap, VecDeque};
+    use std::fs::{self, File, OpenOptions};
+    use std::io::prelude::*;
+    use std::io::{BufReader, Error};
+    use std::path::{Path, PathBuf};
+    use std::sync::Mutex;
+
+    // Anything that can durably record a Metric.
+    // Implementations are shared across connections, so they must handle their own locking.
+    pub trait MetricStore: Send + Sync {
+        // record a single metric
+        fn append(&self, metric: &Metric) -> Result<(), Error>;
+
+        // record several metrics as one unit, either all of them are stored or none are
+        fn append_batch(&self, metrics: &[Metric]) -> Result<(), Error>;
+
+        // push anything still buffered down to durable storage
+        fn flush(&self) -> Result<(), Error>;
+
+        // Err when an append right now would fail, used by the readiness check
+        fn check_writable(&self) -> Result<(), Error>;
+
+        // Hands every metric the store has recorded to visit, oldest first, one at a time
+        // so the whole log never has to fit in memory. Ok holds how many there were.
+        fn read_each(&self, visit: &mut dyn FnMut(Metric)) -> Result<usize, Error>;
+    }
+
+    // Narrows a read down to some metrics. Unset fields match anything,
+    // every tag listed must be present on the metric with exactly that value.
+    // since / until are unix millis compared against the metric's event time, since inclusive, until exclusive.
+    #[derive(Default, Debug, Clone)]
+    pub struct MetricFilter {
+        pub metric_type: Option<MetricName>,
+        pub target: Option<String>,
+        pub tags: BTreeMap<String, String>,
+        pub since: Option<u64>,
+        pub until: Option<u64>,
+    }
+
+    impl MetricFilter {
+        pub fn matches(&self, metric: &Metric) -> bool {
+            if let Some(metric_type) = &self.metric_type {
+                if metric.metric_type != *metric_type {
+                    return false;
+                }
+            }
+            if let Some(target) = &self.target {
+                if metric.target != *target {
+                    return false;
+                }
+            }
+            let event_time = metric.get_event_time();
+            if matches!(self.since, Some(since) if event_time < since) {
+                return false;
+            }
+            if matches!(self.until, Some(until) if event_time >= until) {
+                return false;
+            }
+            self.tags.iter().all(|(key, value)| metric.tags.get(key) == Some(value))
+        }
+    }
+
+    // The most recent metrics, in memory, so reads don't have to go back through the whole log.
+    // Filled from the log at startup and kept up to date by every write, once it holds capacity
+    // metrics the oldest one is pushed out for each new one.
+    pub struct RecentMetrics {
+        capacity: usize,
+        metrics: Mutex<VecDeque<Metric>>,
+    }
+
+    impl RecentMetrics {
+        pub fn new(capacity: usize) -> RecentMetrics {
+            RecentMetrics {
+                capacity,
+                metrics: Mutex::new(VecDeque::new()),
+            }
+        }
+
+        pub fn push(&self, metric: Metric) {
+            let mut recent = self.metrics.lock().unwrap_or_else(|e| e.into_inner());
+            if recent.len() == self.capacity {
+                recent.pop_front();
+            }
+            recent.push_back(metric);
+        }
+
+        // metrics must be oldest first, as they were written
+        pub fn push_all(&self, metrics: &[Metric]) {
+            let mut recent = self.metrics.lock().unwrap_or_else(|e| e.into_inner());
+            // only the last capacity of them could stay anyway
+            let skipped = metrics.len().saturating_sub(self.capacity);
+            for metric in metrics[skipped..].iter() {
+                if recent.len() == self.capacity {
+                    recent.pop_front();
+                }
+                recent.push_back(metric.clone());
+            }
+        }
+
+        // the held metrics that match the filter, oldest first
+        pub fn find(&self, filter: &MetricFilter) -> Vec<Metric> {
+            let recent = self.metrics.lock().unwrap_or_else(|e| e.into_inner());
+            recent.iter().filter(|metric| filter.matches(metric)).cloned().collect()
+        }
+
+        pub fn get_capacity(&self) -> usize {
+            self.capacity
+        }
+    }
+
+    // An append-only log on disk, one JSON encoded Metric per line.
+    // Lines are only ever added to the end of the file, so a crash can at worst
+    // leave a partially written final line, which read_each skips over.
+    pub struct AppendLogStore {
+        path: PathBuf,
+        writer: Mutex<File>,
+    }
+
+    impl AppendLogStore {
+        pub fn open(path: &Path) -> Result<AppendLogStore, Error> {
+            if let Some(parent) = path.parent() {
+                if !parent.as_os_str().is_empty() {
+                    fs::create_dir_all(parent)?;
+                }
+            }
+
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+            Ok(AppendLogStore {
+                path: path.to_path_buf(),
+                writer: Mutex::new(file),
+            })
+        }
+    }
+
+    impl MetricStore for AppendLogStore {
+        fn append(&self, metric: &Metric) -> Result<(), Error> {
+            let mut line = serde_json::to_string(metric)?;
+            line.push('\n');
+
+            // a poisoned lock only means another writer panicked mid-request;
+            // the file handle itself is still usable
+            let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+            // the line goes straight to the OS (no userspace buffer) so it survives a process crash,
+            // flush() is what forces it onto the disk
+            writer.write_all(line.as_bytes())
+        }
+
+        fn append_batch(&self, metrics: &[Metric]) -> Result<(), Error> {
+            // serialize everything up front, so a bad metric can't leave half a batch behind
+            let mut lines = String::new();
+            for metric in metrics {
+                lines.push_str(&serde_json::to_string(metric)?);
+                lines.push('\n');
+            }
+
+            let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+            let length_before = writer.metadata()?.len();
+
+            if let Err(e) = writer.write_all(lines.as_bytes()) {
+                // cut the log back to where it was, nothing of a failed batch should be read back later
+                if let Err(truncate_error) = writer.set_len(length_before) {
+                    println!(
+                        "[error]: could not roll back partial batch in {} ({})",
+                        self.path.display(),
+                        truncate_error
+                    );
+                }
+                return Err(e);
+            }
+
+            Ok(())
+        }
+
+        fn flush(&self) -> Result<(), Error> {
+            let writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+            writer.sync_all()
+        }
+
+        fn check_writable(&self) -> Result<(), Error> {
+            // Reopening (without create) catches a log that was deleted or moved out from under us,
+            // which the open handle would happily keep writing into, as well as lost permissions
+            // and read-only remounts.
+            OpenOptions::new().append(true).open(&self.path)?;
+            Ok(())
+        }
+
+        fn read_each(&self, visit: &mut dyn FnMut(Metric)) -> Result<usize, Error> {
+            let file = File::open(&self.path)?;
+            let reader = BufReader::new(file);
+            let mut count = 0;
+
+            for (line_index, line) in reader.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str::<Metric>(&line) {
+                    Ok(metric) => {
+                        count += 1;
+                        visit(metric);
+                    }
+                    Err(e) => println!(
+                        "[warn]: skipping unreadable metric log entry at {}:{} ({})",
+                        self.path.display(),
+                        line_index + 1,
+                        e
+                    ),
+                }
+            }
+
+            Ok(count)
+        }
+    }
+}
@@ -0,0 +1,200 @@
+pub mod thread_pool {
+
+    // A fixed set of worker threads fed through a bounded queue.
+    // https://doc.rust-lang.org/book/ch20-02-multithreaded.html
+    //
+    // Unlike the book's pool, the queue has a fixed depth, and dispatching onto a full
+    // queue hands the job straight back to the caller instead of blocking the accept loop,
+    // so the caller can shed load (answer 503) rather than stall every other client.
+
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+    use std::sync::{Arc, Mutex};
+    use std::thread::{self, JoinHandle};
+    use std::time::{Duration, Instant};
+
+    // How busy the pool is, shared so anyone holding a clone can look (readiness checks, metrics).
+    pub struct PoolStats {
+        worker_count: usize,
+        queue_depth: usize,
+        // jobs dispatched that no worker has picked up yet
+        queued: AtomicUsize,
+        // workers running a job right now
+        busy: AtomicUsize,
+    }
+
+    impl PoolStats {
+        pub fn new(worker_count: usize, queue_depth: usize) -> PoolStats {
+            PoolStats {
+                worker_count,
+                queue_depth,
+                queued: AtomicUsize::new(0),
+                busy: AtomicUsize::new(0),
+            }
+        }
+
+        pub fn get_worker_count(&self) -> usize {
+            self.worker_count
+        }
+
+        pub fn get_queue_depth(&self) -> usize {
+            self.queue_depth
+        }
+
+        pub fn get_queued(&self) -> usize {
+            self.queued.load(Ordering::SeqCst)
+        }
+
+        pub fn get_busy(&self) -> usize {
+            self.busy.load(Ordering::SeqCst)
+        }
+
+        // the next dispatch would be turned away
+        pub fn is_saturated(&self) -> bool {
+            self.get_queued() >= self.queue_depth
+        }
+    }
+
+    pub struct ThreadPool<T: Send + 'static> {
+        workers: Vec<Worker>,
+        sender: Option<SyncSender<T>>,
+        stats: Arc<PoolStats>,
+    }
+
+    struct Worker {
+        id: usize,
+        thread: Option<JoinHandle<()>>,
+    }
+
+    impl<T: Send + 'static> ThreadPool<T> {
+        // stats says how many worker threads to run (worker_count) and how many jobs are allowed
+        // to wait for a free worker (queue_depth), both must be greater than zero.
+        // The pool keeps stats up to date as jobs come and go.
+        pub fn new<F>(stats: Arc<PoolStats>, handler: F) -> ThreadPool<T>
+        where
+            F: Fn(T) + Send + Sync + 'static,
+        {
+            let size = stats.get_worker_count();
+            let queue_depth = stats.get_queue_depth();
+            assert!(size > 0, "thread pool needs at least one worker");
+            assert!(queue_depth > 0, "thread pool needs a queue depth of at least one");
+
+            let (sender, receiver) = mpsc::sync_channel::<T>(queue_depth);
+            let receiver = Arc::new(Mutex::new(receiver));
+            let handler = Arc::new(handler);
+
+            let mut workers = Vec::with_capacity(size);
+            for id in 0..size {
+                workers.push(Worker::new(id, Arc::clone(&receiver), Arc::clone(&handler), Arc::clone(&stats)));
+            }
+
+            ThreadPool {
+                workers,
+                sender: Some(sender),
+                stats,
+            }
+        }
+
+        // Queue a job for the next free worker.
+        // If the queue is full (or the pool is shutting down) the job is handed back as Err.
+        pub fn try_dispatch(&self, job: T) -> Result<(), T> {
+            let sender = match &self.sender {
+                Some(sender) => sender,
+                None => return Err(job),
+            };
+
+            // counted before sending, a worker could otherwise pick the job up (and count it off) first
+            self.stats.queued.fetch_add(1, Ordering::SeqCst);
+            let sent = sender.try_send(job);
+            if sent.is_err() {
+                self.stats.queued.fetch_sub(1, Ordering::SeqCst);
+            }
+
+            match sent {
+                Ok(()) => Ok(()),
+                Err(TrySendError::Full(job)) => Err(job),
+                Err(TrySendError::Disconnected(job)) => Err(job),
+            }
+        }
+
+        // Stop taking new jobs and give the workers up to `grace` to finish whatever is
+        // queued or running. Returns how many workers were still busy when the deadline
+        // passed; those threads are detached and die with the process.
+        pub fn shutdown(mut self, grace: Duration) -> usize {
+            drop(self.sender.take());
+
+            let deadline = Instant::now() + grace;
+            while Instant::now() < deadline && self.busy_worker_count() > 0 {
+                thread::sleep(Duration::from_millis(25));
+            }
+
+            let mut abandoned = 0;
+            for worker in &mut self.workers {
+                if let Some(thread) = worker.thread.take() {
+                    if !thread.is_finished() {
+                        abandoned += 1;
+                    } else if thread.join().is_err() {
+                        println!("[warn]: worker {} exited abnormally", worker.id);
+                    }
+                }
+            }
+            abandoned
+        }
+
+        fn busy_worker_count(&self) -> usize {
+            self.workers
+                .iter()
+                .filter(|worker| matches!(&worker.thread, Some(thread) if !thread.is_finished()))
+                .count()
+        }
+    }
+
+    impl<T: Send + 'static> Drop for ThreadPool<T> {
+        fn drop(&mut self) {
+            // dropping the sender closes the channel, each worker exits once the queue is drained
+            drop(self.sender.take());
+
+            for worker in &mut self.workers {
+                if let Some(thread) = worker.thread.take() {
+                    if thread.join().is_err() {
+                        println!("[warn]: worker {} exited abnormally", worker.id);
+                    }
+                }
+            }
+        }
+    }
+
+    impl Worker {
+        fn new<T, F>(id: usize, receiver: Arc<Mutex<Receiver<T>>>, handler: Arc<F>, stats: Arc<PoolStats>) -> Worker
+        where
+            T: Send + 'static,
+            F: Fn(T) + Send + Sync + 'static,
+        {
+            let thread = thread::spawn(move || loop {
+                // the lock is released as soon as recv returns, before the job runs
+                let message = receiver.lock().unwrap_or_else(|e| e.into_inner()).recv();
+
+                match message {
+                    Ok(job) => {
+                        stats.queued.fetch_sub(1, Ordering::SeqCst);
+                        stats.busy.fetch_add(1, Ordering::SeqCst);
+                        // a panicking job must not take the worker down with it,
+                        // otherwise the pool slowly shrinks to nothing
+                        let outcome = panic::catch_unwind(AssertUnwindSafe(|| handler(job)));
+                        if outcome.is_err() {
+                            println!("[error]: worker {} recovered from a panicking job", id);
+                        }
+                        stats.busy.fetch_sub(1, Ordering::SeqCst);
+                    }
+                    Err(_) => break,
+                }
+            });
+
+            Worker {
+                id,
+                thread: Some(thread),
+            }
+        }
+    }
+}
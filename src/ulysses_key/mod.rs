@@ -0,0 +1,133 @@
+pub mod ulysses_key {
+
+    // The hub never holds a ulysses key itself, only a salted hash of it, so a leaked .env or
+    // hub.toml can't be replayed as x-ulysses-key. The stored form is
+    //
+    //   sha256$<hex salt>$<hex HMAC-SHA256(pepper, salt || key)>
+    //
+    // The pepper (ULYSSES_KEY_PEPPER) lives apart from the hash, ideally only in the environment,
+    // so the hash alone isn't enough to brute force the key either.
+    // `couch-gag-metrics-hub hash-key` turns a key into this form.
+
+    use crate::utils::utils::{decode_hex, encode_hex};
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+    use std::fmt;
+    use std::fs::File;
+    use std::io::{self, Read};
+
+    type HmacSha256 = Hmac<Sha256>;
+
+    const SCHEME: &str = "sha256";
+    const SALT_LEN: usize = 16;
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct HashedKey {
+        salt: Vec<u8>,
+        digest: Vec<u8>,
+    }
+
+    impl HashedKey {
+        // reads the stored form back, Err says what's wrong with it
+        pub fn parse(stored: &str) -> Result<HashedKey, String> {
+            let parts: Vec<&str> = stored.trim().split('$').collect();
+            if parts.len() != 3 || parts[0] != SCHEME {
+                return Err(String::from("is not a hashed key, expected sha256$<salt>$<digest>"));
+            }
+
+            let salt = match decode_hex(parts[1]) {
+                Some(salt) if !salt.is_empty() => salt,
+                _ => return Err(String::from("has a salt that isn't hex")),
+            };
+            let digest = match decode_hex(parts[2]) {
+                Some(digest) if digest.len() == 32 => digest,
+                _ => return Err(String::from("has a digest that isn't 32 bytes of hex")),
+            };
+
+            Ok(HashedKey { salt, digest })
+        }
+
+        // hashes a key under a fresh random salt
+        pub fn hash(key: &str, pepper: &str) -> io::Result<HashedKey> {
+            let mut salt = vec![0u8; SALT_LEN];
+            File::open("/dev/urandom")?.read_exact(&mut salt)?;
+
+            let digest = new_mac(pepper, &salt, key).finalize().into_bytes().to_vec();
+            Ok(HashedKey { salt, digest })
+        }
+
+        // whether the key a client presented hashes to this one, compared in constant time
+        pub fn verify(&self, presented: &str, pepper: &str) -> bool {
+            new_mac(pepper, &self.salt, presented).verify_slice(&self.digest).is_ok()
+        }
+    }
+
+    // the stored form, what parse reads back
+    impl fmt::Display for HashedKey {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{}${}${}", SCHEME, encode_hex(&self.salt), encode_hex(&self.digest))
+        }
+    }
+
+    fn new_mac(pepper: &str, salt: &[u8], key: &str) -> HmacSha256 {
+        // HMAC takes keys of any length, an empty pepper included
+        let mut mac = HmacSha256::new_from_slice(pepper.as_bytes()).expect("HMAC accepts keys of any length");
+        mac.update(salt);
+        mac.update(key.as_bytes());
+        mac
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn round_trips_through_the_stored_form() {
+            let hashed = HashedKey::hash("the-key", "pepper").unwrap();
+            let stored = hashed.to_string();
+            assert!(stored.starts_with("sha256$"));
+
+            let parsed = HashedKey::parse(&stored).unwrap();
+            assert_eq!(parsed, hashed);
+            assert!(parsed.verify("the-key", "pepper"));
+        }
+
+        #[test]
+        fn salts_every_hash() {
+            let first = HashedKey::hash("the-key", "pepper").unwrap();
+            let second = HashedKey::hash("the-key", "pepper").unwrap();
+            assert_ne!(first, second);
+            assert!(first.verify("the-key", "pepper") && second.verify("the-key", "pepper"));
+        }
+
+        #[test]
+        fn refuses_a_wrong_key_or_pepper() {
+            let hashed = HashedKey::hash("the-key", "pepper").unwrap();
+            assert!(!hashed.verify("the-key ", "pepper"));
+            assert!(!hashed.verify("another-key", "pepper"));
+            assert!(!hashed.verify("the-key", "other-pepper"));
+            assert!(!hashed.verify("the-key", ""));
+        }
+
+        #[test]
+        fn refuses_malformed_stored_forms() {
+            let digest = "ab".repeat(32);
+            assert!(HashedKey::parse(&format!("sha256$00ff${}", digest)).is_ok());
+
+            for stored in [
+                String::from("the-key-itself"),
+                format!("md5$00ff${}", digest),
+                format!("sha256$00ff${}$extra", digest),
+                format!("sha256$${}", digest),
+                format!("sha256$0g${}", digest),
+                format!("sha256$0ff${}", digest),
+                String::from("sha256$00ff$abcd"),
+                format!("sha256$00ff${}zz", digest),
+            ]
+            .iter()
+            {
+                assert!(HashedKey::parse(stored).is_err(), "{} should not parse", stored);
+            }
+        }
+    }
+}
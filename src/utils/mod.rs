@@ -6,6 +6,7 @@ pub mod utils {
     use std::fs::File;
     use std::io::prelude::*;
     use std::io::Error;
+    use std::time::{SystemTime, UNIX_EPOCH};
 
     // File utils
 
@@ -22,148 +23,101 @@ pub mod utils {
         Ok(s)
     }
 
-    pub fn get_key_value_pair_from_env(file_contents: String, key: &str) -> String {
-        // split the string on newlines (kv pairs)
-        let contents_vec: Vec<&str> = file_contents.split("\n").collect();
-        // create a string to load the ulysses kv pair into
-        let mut key_pair_load_string = String::new();
-
-        // iterate through env key value pairs
-        for key_pair in contents_vec.iter() {
-            // if we find the right key, load it to ulysses_key_pair (empty string)
-            if key_pair.contains(&key) {
-                key_pair_load_string = String::from(*key_pair)
-            }
-        }
+    // clock utils
 
-        // split the key-value pair on "="
-        let selected_key_value_collection: Vec<&str> = key_pair_load_string.split("=").collect();
-        let value = String::from(selected_key_value_collection[1]);
-        value
+    pub fn get_unix_time_millis() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or(0)
     }
 
-    pub fn get_env_file() -> String {
-        // load .env variables
-        let env_file: Result<String, Error> = file_reader(".env");
+    pub fn get_unix_time_secs() -> u64 {
+        get_unix_time_millis() / 1000
+    }
 
-        // create an empty string to load the file contents into
-        let mut contents = String::new();
+    // process environment utils
 
-        if env_file.is_err() {
-            let safe_error: Result<String, Error> = env_file.or_else(|err| Ok(format!("{:?}::{}", err.kind(), err.to_string())));
-            contents = safe_error.and_then(|s| Ok(s)).unwrap();
-        } else {
-            contents = env_file.unwrap();
+    pub fn get_env_var_or(key: &str, default: &str) -> String {
+        match std::env::var(key) {
+            Ok(value) if !value.is_empty() => value,
+            _ => String::from(default),
         }
-
-        contents
     }
 
     // request utils
 
-    pub fn get_url_from_req(req: &String) -> ReqUrl {
-        let mut query_parameters: Vec<(String, String)> = Vec::new();
-        let request_vec: Vec<&str> = req.split("\r\n").collect();
-        let request_line = request_vec[0];
-        let split_request_line: Vec<&str> = request_line.split(" ").collect();
-
-        if split_request_line.len() <= 1 {
-            return ReqUrl {
-                path: String::from(split_request_line[0]),
-                query_parameters: vec![]
-            }
-        }
-        
-        let path_unsanitized = split_request_line[1];
-        let path_vec: Vec<&str> = path_unsanitized.split("?").collect();
-        let sanitized_path = path_vec[0];
-
-        if path_vec.len() > 1 {
-            let query_param_string = path_vec[1];
-
-            let query_param_vec: Vec<&str> = query_param_string.split("&").collect();
-
-            for kv in query_param_vec {
-                let key_value_vec: Vec<&str> = kv.split("=").collect();
-                query_parameters.push((
-                    String::from(key_value_vec[0]),
-                    String::from(key_value_vec[1]),
-                ))
-            }
-        }
+    pub fn get_url_from_req(request: &HttpRequest) -> ReqUrl {
+        let (sanitized_path, query_param_string) = match request.path.split_once('?') {
+            Some((path, query)) => (path, query),
+            None => (request.path.as_str(), ""),
+        };
 
         ReqUrl {
             path: String::from(sanitized_path),
-            query_parameters: query_parameters,
+            query_parameters: parse_form_urlencoded(query_param_string),
         }
     }
 
-    pub fn get_path(req_url: &ReqUrl) -> String {
-        req_url.path.clone()
-    }
+    // "a=1&b=two%20words" -> [("a", "1"), ("b", "two words")]
+    // the same encoding is used by query strings and application/x-www-form-urlencoded bodies
+    pub fn parse_form_urlencoded(encoded: &str) -> Vec<(String, String)> {
+        let mut pairs: Vec<(String, String)> = Vec::new();
 
-    pub fn get_headers_off_req(request: &String) -> Vec<(&str, &str)> {
-        let mut headers: Vec<(&str, &str)> = Vec::new();
-        let request_vec: Vec<&str> = request.split("\r\n").collect();
-        for req_piece in request_vec.iter() {
-            if req_piece.contains(": ") {
-                let split_req_piece: Vec<&str> = req_piece.split(": ").collect();
-                headers.push((split_req_piece[0], split_req_piece[1]));
+        for kv in encoded.split('&') {
+            if kv.is_empty() {
+                continue;
             }
+            // only split on the first "=", a value is allowed to contain more of them
+            let (key, value) = kv.split_once('=').unwrap_or((kv, ""));
+            pairs.push((decode_url_component(key), decode_url_component(value)));
         }
-        headers
-    }
 
-    pub fn get_http_method(request: &String, method: &mut String) {
-        let safe_req_split: Vec<&str> = request.split("\r\n").collect();
-        let req_line = safe_req_split[0];
-        if req_line.contains("GET") {
-            *method = String::from("GET");
-        } else if req_line.contains("POST") {
-            *method = String::from("POST");
-        } else if req_line.contains("OPTIONS") {
-            *method = String::from("OPTIONS");
-        } else {
-            *method = String::from("OTHER");
-        }
+        pairs
     }
 
-    // Validity check
-
-    pub fn has_valid_ulysses_key(request: &HttpRequest) -> bool {
-        let file_contents = get_env_file();
-        // this is the flag that gets passed back as the return value
+    // undoes application/x-www-form-urlencoded escaping, "+" for spaces and %XX for everything else
+    // malformed escapes are passed through untouched rather than rejected
+    pub fn decode_url_component(component: &str) -> String {
+        let bytes = component.as_bytes();
+        let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+        let mut index = 0;
+
+        while index < bytes.len() {
+            match bytes[index] {
+                b'+' => decoded.push(b' '),
+                b'%' if index + 2 < bytes.len() && is_hex_pair(bytes[index + 1], bytes[index + 2]) => {
+                    let hex = [bytes[index + 1], bytes[index + 2]];
+                    let hex_str = String::from_utf8_lossy(&hex);
+                    decoded.push(u8::from_str_radix(&hex_str, 16).unwrap_or(b'%'));
+                    index += 2;
+                }
+                other => decoded.push(other),
+            }
+            index += 1;
+        }
 
-        let ulysses_key = get_key_value_pair_from_env(file_contents, "ULYSSES_HASHED_KEY");
-        // get headers off the request
+        String::from_utf8_lossy(&decoded).into_owned()
+    }
 
-        let req_ulysses_key = request.get_header_by_key(String::from("x-ulysses-key"));
-        ulysses_key == req_ulysses_key
+    fn is_hex_pair(a: u8, b: u8) -> bool {
+        a.is_ascii_hexdigit() && b.is_ascii_hexdigit()
     }
 
+    // hex utils, for hashes and signatures
 
-    pub fn is_valid_path(path: &String) -> bool {
-        if path.eq("/") {
-            true
-        } else if path.eq("/ping") {
-            true
-        } else if path.eq("/metric") {
-            true
-        } else {
-            false
-        }
+    pub fn encode_hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
     }
 
-    // response utils
-
-    pub fn add_headers_to_response(response: &mut String, headers: &Vec<(String, String)>) {
-        let final_index = headers.len() - 1;
-        for header in headers.iter() {
-            response.push_str(&*format!("{}: {}", header.0, header.1));
-            if header != headers.get(final_index).unwrap() {
-                response.push_str("\n");
-            }
+    pub fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+        if hex.len() % 2 != 0 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
         }
+
+        (0..hex.len())
+            .step_by(2)
+            .map(|index| u8::from_str_radix(&hex[index..index + 2], 16).ok())
+            .collect()
     }
-    
 }